@@ -1,186 +1,836 @@
+use super::metrics::Direction;
 use super::state::CdState;
+use super::storage::{InventoryStore, PendingInbound, StockChange};
 use actix_web::web;
+use chrono::Utc;
 use common_models::{
-    Product, ProductAvailability, ServiceInfoLookup, TransferRequest,
+    ApiError, AvailabilityResponse, Currency, ErrorCode, InventorySnapshot, Money, MovementReason,
+    Product, ProductAvailability, ProductRequest, ProductRequestState, ProductRequestStatus,
+    Reservation, ReservationRequest, ServiceInfo, ServiceType, StockEvent, StockEventBatch,
 };
-use tokio::time::{sleep, Duration};
+use futures::stream::{FuturesUnordered, StreamExt};
+use service_clients::CdClient;
+use std::io;
+use std::sync::atomic::Ordering;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, warn, Instrument};
+use uuid::Uuid;
 
+const RESERVATION_TTL_SECS: u64 = 30;
+const COMMIT_ATTEMPTS: u32 = 3;
+const REGISTER_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REGISTER_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const STOCK_EVENTS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const STOCK_EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+const STOCK_EVENTS_RETRY_DELAY: Duration = Duration::from_secs(2);
+const STOCK_EVENTS_BATCH_SIZE: usize = 500;
+
+/// Registers a product request and runs the sourcing flow for it in the
+/// background. Progress is visible through `GET /requests/{request_id}`.
+pub fn start_product_request(
+    state: web::Data<CdState>,
+    request: ProductRequest,
+) -> ProductRequestStatus {
+    let now = Utc::now();
+    let status = ProductRequestStatus {
+        request_id: Uuid::new_v4().to_string(),
+        product_code: request.product_code,
+        quantity: request.quantity,
+        state: ProductRequestState::Pending,
+        quantity_received: 0,
+        progress: "Waiting to start".to_string(),
+        error: None,
+        created_at: now,
+        updated_at: now,
+    };
+    state
+        .product_requests
+        .lock()
+        .unwrap()
+        .insert(status.request_id.clone(), status.clone());
+
+    let request_id = status.request_id.clone();
+    let product_code = status.product_code.clone();
+    let quantity = status.quantity;
+    // O pedido continua o trace da chamada que o criou, se houver.
+    let span = tracing::info_span!(
+        "product_request",
+        request_id = %request_id,
+        product_code = %product_code,
+        quantity
+    );
+    tokio::spawn(
+        async move {
+            report_progress(&state, Some(&request_id), |r| {
+                r.state = ProductRequestState::Running;
+            });
+            let result = request_product_from_system(
+                state.clone(),
+                product_code.clone(),
+                quantity,
+                Some(&request_id),
+            )
+            .await;
+            let outcome = if result.is_ok() {
+                "completed"
+            } else {
+                "failed"
+            };
+            state
+                .metrics
+                .product_requests
+                .with_label_values(&[outcome])
+                .inc();
+            report_progress(&state, Some(&request_id), |r| match &result {
+                Ok(()) => {
+                    r.state = ProductRequestState::Completed;
+                    r.progress = "Done".to_string();
+                }
+                Err(e) => {
+                    r.state = ProductRequestState::Failed;
+                    r.error = Some(e.clone());
+                }
+            });
+            if let Err(e) = result {
+                warn!(
+                    "Error fulfilling request {} for {}: {}",
+                    request_id, product_code, e
+                );
+            }
+        }
+        .instrument(span),
+    );
+
+    status
+}
+
+fn report_progress(
+    state: &CdState,
+    request_id: Option<&str>,
+    update: impl FnOnce(&mut ProductRequestStatus),
+) {
+    let Some(request_id) = request_id else {
+        return;
+    };
+    if let Some(status) = state.product_requests.lock().unwrap().get_mut(request_id) {
+        update(status);
+        status.updated_at = Utc::now();
+    }
+}
+
+/// Makes sure this CD holds at least `quantity_needed` of `product_code`,
+/// sourcing the missing amount from other CDs.
 pub async fn request_product_from_system(
     state: web::Data<CdState>,
     product_code: String,
     quantity_needed: u32,
-) -> Result<(), String> {
-    println!(
-        "[{}] Requesting {} of product {}",
-        state.own_id, quantity_needed, product_code
-    );
+    request_id: Option<&str>,
+) -> Result<(), ApiError> {
+    info!("Requesting {} of product {}", quantity_needed, product_code);
 
     let mut quantity_to_find = quantity_needed;
 
     {
-        let mut current_inventory = state.inventory.lock().unwrap();
-        if let Some(product) = current_inventory.get_mut(&product_code) {
+        let current_inventory = state.inventory.lock().unwrap();
+        if let Some(product) = current_inventory.get(&product_code) {
             let product_current_quantity = product.quantity.unwrap_or(0);
             if product_current_quantity >= quantity_needed {
-                println!(
-                    "[{}] Already have enough of {}. Quantity: {}",
-                    state.own_id, product_code, product_current_quantity
+                info!(
+                    "Already have enough of {}. Quantity: {}",
+                    product_code, product_current_quantity
                 );
                 return Ok(());
             } else {
                 quantity_to_find -= product_current_quantity;
-                println!(
-                    "[{}] Only have {} of {}. Need {} more.",
-                    state.own_id, product_current_quantity, product_code, quantity_to_find
+                info!(
+                    "Only have {} of {}. Need {} more.",
+                    product_current_quantity, product_code, quantity_to_find
                 );
             }
         } else {
-            println!(
-                "[{}] Don't have any of {}. Need {} total.",
-                state.own_id, product_code, quantity_to_find
+            info!(
+                "Don't have any of {}. Need {} total.",
+                product_code, quantity_to_find
             );
         }
     }
 
-    let client = &state.http_client;
-    let hub_who_has_url = format!(
-        "{}/who_has_product/{}/{}",
-        state.hub_url, product_code, quantity_to_find
-    );
-    println!("[{}] Querying Hub: {}", state.own_id, hub_who_has_url);
+    info!("Querying Hub for {} of {}", quantity_to_find, product_code);
+    report_progress(&state, request_id, |r| {
+        r.progress = format!("Querying Hub for {} more", quantity_to_find);
+    });
 
-    let response = client
-        .get(&hub_who_has_url)
-        .send()
+    let availability = match state
+        .hub
+        .who_has_product(&product_code, quantity_to_find, true)
         .await
-        .map_err(|e| format!("Failed to query Hub: {}", e))?;
+    {
+        Ok(availability) => availability,
+        // O Hub responde 404 quando nenhum CD tem o produto.
+        Err(e) if e.code() == Some(ErrorCode::NotFound) => AvailabilityResponse {
+            cds: Vec::new(),
+            partial: false,
+            skipped_cds: Vec::new(),
+        },
+        Err(e) => return Err(ApiError::from(e).context("Failed to query Hub")),
+    };
 
-    let status = response.status();
+    info!(
+        "Hub found these CDs with {}: {:?}",
+        product_code, availability.cds
+    );
+    let skipped: Vec<&str> = availability
+        .skipped_cds
+        .iter()
+        .map(|cd| cd.cd_id.as_str())
+        .collect();
+    if availability.partial {
+        info!("Hub answer is partial, skipped CDs: {:?}", skipped);
+    }
 
-    if !status.is_success() {
-        let error_body = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("Hub returned error {}: {}", status, error_body));
+    let plan = plan_allocation(&state.own_id, availability.cds, quantity_to_find);
+    let planned: u32 = plan.iter().map(|(_, quantity)| quantity).sum();
+    if planned < quantity_to_find {
+        let mut error = format!(
+            "Only {} of {} found across all CDs, {} needed",
+            planned, product_code, quantity_to_find
+        );
+        if availability.partial {
+            error.push_str(&format!(" (Hub could not reach {})", skipped.join(", ")));
+        }
+        return Err(ApiError::new(ErrorCode::InsufficientStock, error));
     }
+    info!("Allocation plan for {}: {:?}", product_code, plan);
 
-    let available_cds: Vec<ProductAvailability> = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse Hub response: {}", e))?;
+    // Fase 1: reservar todas as partes. Se alguma falhar, as outras são liberadas.
+    let mut held: Vec<(String, CdClient, Reservation)> = Vec::new();
+    for (source_cd_id, quantity) in plan {
+        let result = match lookup_cd(&state, &source_cd_id).await {
+            Ok(source) => reserve_from(&state, &source_cd_id, &source, &product_code, quantity)
+                .await
+                .map(|reservation| (source, reservation)),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((source, reservation)) => held.push((source_cd_id, source, reservation)),
+            Err(e) => {
+                warn!(
+                    "Failed to reserve {} of {} at {}: {}",
+                    quantity, product_code, source_cd_id, e
+                );
+                for (cd_id, source, reservation) in &held {
+                    info!(
+                        "Rolling back reservation {} at {}",
+                        reservation.reservation_id, cd_id
+                    );
+                    abort_reservation(source, &reservation.reservation_id).await;
+                }
+                return Err(e.context(&format!(
+                    "Could not fulfill request for {} of {}: reservation at {} failed",
+                    quantity_needed, product_code, source_cd_id
+                )));
+            }
+        }
+    }
 
-    if available_cds.is_empty() {
-        return Err(format!(
-            "No CD found with product {} (quantity {})",
-            product_code, quantity_to_find
-        ));
+    // Fase 2: confirmar as reservas todas ao mesmo tempo. Em sequência, as
+    // novas tentativas de um CD poderiam deixar as reservas seguintes expirarem
+    // depois de as primeiras já terem sido confirmadas.
+    report_progress(&state, request_id, |r| {
+        r.progress = format!("Committing {} reservation(s)", held.len());
+    });
+    let cd_state = &state;
+    let mut commits: FuturesUnordered<_> = held
+        .into_iter()
+        .map(|(source_cd_id, source, reservation)| async move {
+            let quantity = reservation.quantity;
+            let result = commit_reserved(cd_state, &source_cd_id, &source, reservation).await;
+            (source_cd_id, quantity, result)
+        })
+        .collect();
+    let mut received = 0;
+    let mut failures = Vec::new();
+    while let Some((source_cd_id, quantity, result)) = commits.next().await {
+        match result {
+            Ok(true) => {
+                info!(
+                    "Successfully transferred {} of {} from {}",
+                    quantity, product_code, source_cd_id
+                );
+                received += quantity;
+                report_progress(&state, request_id, |r| r.quantity_received = received);
+            }
+            Ok(false) => failures.push(ApiError::new(
+                ErrorCode::ReservationExpired,
+                format!(
+                    "reservation at {} was no longer valid at commit time",
+                    source_cd_id
+                ),
+            )),
+            Err(e) => failures.push(e.context(&source_cd_id)),
+        }
     }
 
-    println!(
-        "[{}] Hub found these CDs with {}: {:?}",
-        state.own_id, product_code, available_cds
+    info!(
+        "Current inventory for {}: {:?}",
+        product_code,
+        state.inventory.lock().unwrap().get(&product_code)
     );
 
-    for cd_availability in available_cds {
-        if cd_availability.quantity_available >= quantity_to_find {
-            let target_cd_id = cd_availability.cd_id;
-            println!(
-                "[{}] Trying to get {} of {} from CD: {}",
-                state.own_id, quantity_to_find, product_code, target_cd_id
+    // Uma falha aqui não desfaz o que outros CDs já enviaram: o pedido guarda
+    // quanto chegou para quem for consultá-lo depois.
+    if !failures.is_empty() {
+        warn!(
+            "Only {} of {} {} arrived before the failures",
+            received, quantity_to_find, product_code
+        );
+        report_progress(&state, request_id, |r| {
+            r.quantity_received = received;
+            r.progress = format!(
+                "Received {} of {} more; {} commit(s) failed",
+                received,
+                quantity_to_find,
+                failures.len()
             );
+        });
+    }
 
-            let lookup_url = format!("{}/lookup/{}", state.service_discovery_url, target_cd_id);
-            let target_cd_info: ServiceInfoLookup = client
-                .get(&lookup_url)
-                .send()
-                .await
-                .map_err(|e| format!("Failed to lookup target CD {}: {}", target_cd_id, e))?
-                .json()
-                .await
-                .map_err(|e| format!("Failed to parse target CD info: {}", e))?;
+    // O código do erro é o da primeira falha; a mensagem lista todas.
+    match failures.first() {
+        None => Ok(()),
+        Some(first) => Err(ApiError::new(
+            first.code,
+            format!(
+                "Received {} of {} {} needed: {}",
+                received,
+                quantity_to_find,
+                product_code,
+                failures
+                    .iter()
+                    .map(|e| e.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        )),
+    }
+}
 
-            let transfer_url = format!(
-                "http://{}:{}/transfer_product",
-                target_cd_info.ip, target_cd_info.port
-            );
-            println!(
-                "[{}] Sending transfer request to {}: {}",
-                state.own_id, target_cd_id, transfer_url
-            );
+/// Splits `quantity` across the other CDs, taking as much as possible from
+/// the CDs with the most stock first so the order touches as few CDs as it can.
+fn plan_allocation(
+    own_id: &str,
+    mut available_cds: Vec<ProductAvailability>,
+    quantity: u32,
+) -> Vec<(String, u32)> {
+    available_cds.retain(|cd| cd.cd_id != own_id && cd.quantity_available > 0);
+    available_cds.sort_by_key(|cd| std::cmp::Reverse(cd.quantity_available));
 
-            let transfer_request_body = TransferRequest {
-                product_code: product_code.clone(),
-                quantity: quantity_to_find,
-                requester_cd_id: state.own_id.clone(),
-            };
+    let mut remaining = quantity;
+    let mut plan = Vec::new();
+    for cd in available_cds {
+        if remaining == 0 {
+            break;
+        }
+        let take = cd.quantity_available.min(remaining);
+        plan.push((cd.cd_id, take));
+        remaining -= take;
+    }
+    plan
+}
 
-            let transfer_response = client
-                .post(&transfer_url)
-                .json(&transfer_request_body)
-                .send()
-                .await
+async fn lookup_cd(state: &CdState, cd_id: &str) -> Result<CdClient, ApiError> {
+    let cd_info =
+        state.discovery.lookup(cd_id).await.map_err(|e| {
+            ApiError::from(e).context(&format!("Failed to lookup target CD {}", cd_id))
+        })?;
+    CdClient::for_service(state.http_client.clone(), &cd_info)
+        .map_err(|e| ApiError::from(e).context(&format!("Unusable address for CD {}", cd_id)))
+}
+
+/// Reserves `quantity` of `product_code` at the source CD.
+async fn reserve_from(
+    state: &web::Data<CdState>,
+    source_cd_id: &str,
+    source: &CdClient,
+    product_code: &str,
+    quantity: u32,
+) -> Result<Reservation, ApiError> {
+    let reservation_request = ReservationRequest {
+        reservation_id: Uuid::new_v4().to_string(),
+        product_code: product_code.to_string(),
+        quantity,
+        requester_cd_id: state.own_id.clone(),
+        ttl_secs: RESERVATION_TTL_SECS,
+    };
+    info!(
+        "Sending reservation request to {} at {}",
+        source_cd_id,
+        source.base_url()
+    );
+
+    match source.reserve(&reservation_request).await {
+        Ok(reservation) => Ok(reservation),
+        Err(e) if e.is_unreachable() => {
+            // The reservation may exist at the source; abort it instead of
+            // waiting for it to expire.
+            abort_reservation(source, &reservation_request.reservation_id).await;
+            Err(ApiError::from(e).context("Failed to send reservation request"))
+        }
+        Err(e) => Err(ApiError::from(e).context("Reservation refused")),
+    }
+}
+
+/// Durably records the inbound transfer for `reservation` and commits it.
+/// Returns `Ok(false)` if the source no longer honours the reservation, and
+/// `Err` if the outcome is still unknown (in which case
+/// `resolve_pending_inbound` finishes it later).
+async fn commit_reserved(
+    state: &web::Data<CdState>,
+    source_cd_id: &str,
+    source: &CdClient,
+    reservation: Reservation,
+) -> Result<bool, ApiError> {
+    let pending = PendingInbound {
+        reservation_id: reservation.reservation_id.clone(),
+        source_cd_id: source_cd_id.to_string(),
+        product: reservation.product,
+        quantity: reservation.quantity,
+        created_at: Utc::now(),
+    };
+    let recorded = state.inventory.lock().unwrap().put_inbound(pending.clone());
+    if let Err(e) = recorded {
+        abort_reservation(source, &pending.reservation_id).await;
+        return Err(ApiError::new(
+            ErrorCode::Internal,
+            format!("Failed to record inbound transfer: {}", e),
+        ));
+    }
+
+    let mut last_error = None;
+    for attempt in 1..=COMMIT_ATTEMPTS {
+        match commit_inbound(state, source, &pending).await {
+            Ok(committed) => return Ok(committed),
+            Err(e) => {
+                warn!(
+                    "Commit attempt {} of reservation {} failed: {}",
+                    attempt, pending.reservation_id, e
+                );
+                last_error = Some(e);
+            }
+        }
+        sleep(Duration::from_secs(attempt as u64)).await;
+    }
+    let last_error = last_error.expect("COMMIT_ATTEMPTS is at least one");
+    Err(last_error.context(&format!(
+        "Commit of reservation {} still pending",
+        pending.reservation_id
+    )))
+}
+
+/// Asks the source CD to commit `pending` and settles it locally. `Ok(true)`
+/// means the stock was credited, `Ok(false)` that the reservation was dropped
+/// by the source. `Err` leaves the transfer pending.
+async fn commit_inbound(
+    state: &CdState,
+    source: &CdClient,
+    pending: &PendingInbound,
+) -> Result<bool, ApiError> {
+    match source.commit_reservation(&pending.reservation_id).await {
+        Ok(reservation) => {
+            let credited =
+                credit_inbound(&mut state.inventory.lock().unwrap(), pending, &reservation)
+                    .map_err(|e| {
+                        ApiError::new(
+                            ErrorCode::Internal,
+                            format!("Failed to persist received stock: {}", e),
+                        )
+                    })?;
+            if credited {
+                state
+                    .metrics
+                    .record_transfer(Direction::In, reservation.quantity);
+            }
+            Ok(true)
+        }
+        Err(e)
+            if matches!(
+                e.code(),
+                Some(
+                    ErrorCode::ReservationExpired
+                        | ErrorCode::ReservationAborted
+                        | ErrorCode::NotFound
+                )
+            ) =>
+        {
+            let mut inventory = state.inventory.lock().unwrap();
+            if !inventory.is_pending_inbound(&pending.reservation_id) {
+                // Settled meanwhile by the other commit loop.
+                return Ok(was_credited(&inventory, &pending.reservation_id));
+            }
+            inventory
+                .finish_inbound(&pending.reservation_id, None)
                 .map_err(|e| {
-                    format!("Failed to send transfer request to {}: {}", target_cd_id, e)
+                    ApiError::new(
+                        ErrorCode::Internal,
+                        format!("Failed to clear pending transfer: {}", e),
+                    )
                 })?;
+            Ok(false)
+        }
+        Err(e) => Err(ApiError::from(e).context("Commit failed")),
+    }
+}
 
-            let transfer_status = transfer_response.status();
+/// Credits a transfer the source committed. Both the commit retries and
+/// `resolve_pending_inbound` may get the commit confirmed, and the source
+/// confirms an already committed reservation again, so only the first one to
+/// get here credits the stock; later ones get `false`.
+fn credit_inbound(
+    inventory: &mut InventoryStore,
+    pending: &PendingInbound,
+    reservation: &Reservation,
+) -> io::Result<bool> {
+    if !inventory.is_pending_inbound(&pending.reservation_id) {
+        return Ok(false);
+    }
+    let Some(product) = credited_product(inventory, &reservation.product, reservation.quantity)
+    else {
+        // Fica pendente: o crédito é tentado de novo quando houver espaço.
+        error!(
+            "Crediting {} of {} from reservation {} would overflow the stock",
+            reservation.quantity, reservation.product.code, pending.reservation_id
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stock quantity would overflow",
+        ));
+    };
+    let credited = StockChange {
+        product,
+        reason: MovementReason::TransferIn,
+        counterparty_cd_id: Some(pending.source_cd_id.clone()),
+        request_id: Some(pending.reservation_id.clone()),
+    };
+    inventory.finish_inbound(&pending.reservation_id, Some(credited))?;
+    Ok(true)
+}
 
-            if transfer_status.is_success() {
-                println!(
-                    "[{}] Successfully transferred {} of {} from {}",
-                    state.own_id, quantity_to_find, product_code, target_cd_id
-                );
+fn was_credited(inventory: &InventoryStore, reservation_id: &str) -> bool {
+    inventory.ledger().iter().rev().any(|m| {
+        m.reason == MovementReason::TransferIn && m.request_id.as_deref() == Some(reservation_id)
+    })
+}
 
-                {
-                    let mut inventory = state.inventory.lock().unwrap();
-                    inventory
-                        .entry(product_code.clone())
-                        .and_modify(|p| {
-                            p.quantity = Some(p.quantity.unwrap_or(0) + quantity_to_find)
-                        })
-                        .or_insert(Product {
-                            code: product_code.clone(),
-                            name: cd_availability.product_info.name,
-                            price: cd_availability.product_info.price,
-                            quantity: Some(quantity_to_find),
-                        });
-                    println!(
-                        "[{}] Current inventory for {}: {:?}",
-                        state.own_id,
-                        product_code,
-                        inventory.get(&product_code)
-                    );
-                }
+async fn abort_reservation(source: &CdClient, reservation_id: &str) {
+    if let Err(e) = source.abort_reservation(reservation_id).await {
+        warn!(
+            "Failed to abort reservation {} (it will expire at the source): {}",
+            reservation_id, e
+        );
+    }
+}
 
-                return Ok(());
-            } else {
-                let error_body = transfer_response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Unknown error".to_string());
-                eprintln!(
-                    "[{}] Failed to transfer from {}: {}: {}",
-                    state.own_id, target_cd_id, transfer_status, error_body
+/// `product` after `quantity` more units arrive; `None` if the stock would
+/// not fit in a `u32`.
+fn credited_product(
+    inventory: &InventoryStore,
+    product: &Product,
+    quantity: u32,
+) -> Option<Product> {
+    match inventory.get(&product.code) {
+        Some(p) => Some(Product {
+            quantity: Some(p.quantity.unwrap_or(0).checked_add(quantity)?),
+            ..p.clone()
+        }),
+        None => Some(Product {
+            quantity: Some(quantity),
+            ..product.clone()
+        }),
+    }
+}
+
+/// Retries commits of inbound transfers whose outcome is unknown, including
+/// those left over from before a restart.
+pub async fn resolve_pending_inbound(state: web::Data<CdState>) {
+    loop {
+        let pending: Vec<PendingInbound> = state
+            .inventory
+            .lock()
+            .unwrap()
+            .pending_inbound()
+            .cloned()
+            .collect();
+
+        for transfer in pending {
+            let Ok(source) = lookup_cd(&state, &transfer.source_cd_id).await else {
+                continue;
+            };
+
+            match commit_inbound(&state, &source, &transfer).await {
+                Ok(true) => info!(
+                    "Recovered transfer of {} of {} from {}",
+                    transfer.quantity, transfer.product.code, transfer.source_cd_id
+                ),
+                Ok(false) => info!(
+                    "Dropped pending transfer {} rejected by {}",
+                    transfer.reservation_id, transfer.source_cd_id
+                ),
+                Err(e) => warn!(
+                    "Pending transfer {} still unresolved: {}",
+                    transfer.reservation_id, e
+                ),
+            }
+        }
+
+        sleep(Duration::from_secs(10)).await;
+    }
+}
+
+/// Registers this CD with Service Discovery, retrying with exponential
+/// backoff until it succeeds.
+pub async fn register_with_discovery(state: &CdState) {
+    let mut backoff = REGISTER_INITIAL_BACKOFF;
+
+    while !state.shutting_down.load(Ordering::SeqCst) {
+        let service_info = ServiceInfo {
+            id: state.own_id.clone(),
+            ip: state.advertised_ip.clone(),
+            port: state.advertised_port,
+            scheme: state.advertised_scheme.to_string(),
+            service_type: ServiceType::Cd,
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            tags: state.tags.clone(),
+            metadata: state.metadata.clone(),
+            last_heartbeat: Utc::now(),
+        };
+        match state.discovery.register(&service_info).await {
+            Ok(()) => {
+                info!(
+                    "Registered with Service Discovery at {}:{}",
+                    state.advertised_ip, state.advertised_port
                 );
+                return;
             }
+            Err(e) => warn!("Failed to register with Service Discovery: {}", e),
         }
+
+        info!("Retrying registration in {:?}", backoff);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(REGISTER_MAX_BACKOFF);
     }
+}
 
-    Err(format!(
-        "Could not fulfill request for {} of {} from any available CD",
-        quantity_needed, product_code
-    ))
+/// Removes this CD from Service Discovery so peers and the hub stop routing
+/// work to it. Failure is only logged: the entry then ages out on its own.
+pub async fn deregister_from_discovery(state: &CdState) {
+    match state.discovery.deregister(&state.own_id).await {
+        Ok(()) => info!("Deregistered from Service Discovery"),
+        Err(e) => warn!("Failed to deregister from Service Discovery: {}", e),
+    }
 }
 
+/// Keeps this CD registered: registers on startup, then sends heartbeats and
+/// registers again whenever Service Discovery no longer knows this CD (it was
+/// evicted as inactive or Service Discovery restarted).
 pub async fn send_heartbeat(state: web::Data<CdState>) {
-    let client = &state.http_client;
-    let heartbeat_url = format!("{}/heartbeat/{}", state.service_discovery_url, state.own_id);
+    register_with_discovery(&state).await;
+
     loop {
-        sleep(Duration::from_secs(10)).await;
-        match client.post(&heartbeat_url).send().await {
-            Ok(_) => {}
-            Err(e) => eprintln!("[{}] Failed to send heartbeat: {}", state.own_id, e),
+        sleep(state.heartbeat_interval).await;
+        // Depois do deregister, um heartbeat recusado não deve registrar o CD de novo.
+        if state.shutting_down.load(Ordering::SeqCst) {
+            return;
+        }
+        match state.discovery.heartbeat(&state.own_id).await {
+            Ok(()) => {}
+            Err(e) if e.code() == Some(ErrorCode::NotFound) => {
+                warn!("Service Discovery does not know this CD; registering again");
+                register_with_discovery(&state).await;
+            }
+            Err(e) => warn!("Failed to send heartbeat: {}", e),
         }
     }
 }
+
+pub fn stock_snapshot(own_id: &str, inventory: &InventoryStore) -> InventorySnapshot {
+    let mut products: Vec<Product> = inventory.products().cloned().collect();
+    products.sort_by(|a, b| a.code.cmp(&b.code));
+    InventorySnapshot {
+        cd_id: own_id.to_string(),
+        seq: inventory.last_movement_id(),
+        taken_at: Utc::now(),
+        products,
+    }
+}
+
+/// Stock events for the ledger movements after `after`, with the product
+/// details the hub needs to answer availability queries.
+fn stock_events_after(inventory: &InventoryStore, after: u64) -> Vec<StockEvent> {
+    inventory
+        .movements_after(after)
+        .iter()
+        .take(STOCK_EVENTS_BATCH_SIZE)
+        .map(|movement| {
+            let mut product = inventory
+                .get(&movement.product_code)
+                .cloned()
+                .unwrap_or_else(|| Product {
+                    code: movement.product_code.clone(),
+                    name: movement.product_code.clone(),
+                    price: Money::zero(Currency::default()),
+                    quantity: None,
+                });
+            product.quantity = Some(movement.quantity_after);
+            StockEvent {
+                seq: movement.id,
+                timestamp: movement.timestamp,
+                product,
+            }
+        })
+        .collect()
+}
+
+/// Publishes every stock change to the hub so it can answer availability
+/// queries from its own index. Starts with a full snapshot, then sends the
+/// ledger movements the hub has not acknowledged yet; when the hub reports a
+/// gap it resends from the last event it has, or sends a new snapshot if the
+/// hub lost track of this CD (for example after a restart).
+pub async fn publish_stock_events(state: web::Data<CdState>) {
+    let mut acked: Option<u64> = None;
+    let mut last_sent = Instant::now();
+
+    loop {
+        let outcome = match acked {
+            None => {
+                let snapshot = stock_snapshot(&state.own_id, &state.inventory.lock().unwrap());
+                state.hub.publish_stock_snapshot(&snapshot).await
+            }
+            Some(after) => {
+                let events = stock_events_after(&state.inventory.lock().unwrap(), after);
+                if events.is_empty() && last_sent.elapsed() < STOCK_EVENTS_KEEPALIVE {
+                    sleep(STOCK_EVENTS_POLL_INTERVAL).await;
+                    continue;
+                }
+                // Um lote vazio serve de keepalive: mantém o `as_of` do Hub atualizado e
+                // detecta um Hub reiniciado mesmo sem movimentações.
+                let batch = StockEventBatch {
+                    cd_id: state.own_id.clone(),
+                    events,
+                };
+                state.hub.publish_stock_events(&batch).await
+            }
+        };
+
+        match outcome {
+            Ok(ack) => {
+                acked = ack.last_seq;
+                last_sent = Instant::now();
+            }
+            Err(e) => {
+                warn!("Failed to publish stock events: {}", e);
+                sleep(STOCK_EVENTS_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_models::ReservationStatus;
+    use std::collections::HashMap;
+
+    fn product(quantity: u32) -> Product {
+        Product {
+            code: "P001".to_string(),
+            name: "Widget".to_string(),
+            price: Money::zero(Currency::BRL),
+            quantity: Some(quantity),
+        }
+    }
+
+    #[test]
+    fn committing_a_pending_transfer_twice_credits_it_once() {
+        let dir = std::env::temp_dir().join(format!("cd-service-test-{}", Uuid::new_v4()));
+        let seed = HashMap::from([("P001".to_string(), product(10))]);
+        let mut inventory = InventoryStore::open(&dir, seed, Vec::new()).unwrap();
+
+        let pending = PendingInbound {
+            reservation_id: "r1".to_string(),
+            source_cd_id: "cd_beta".to_string(),
+            product: product(0),
+            quantity: 5,
+            created_at: Utc::now(),
+        };
+        inventory.put_inbound(pending.clone()).unwrap();
+        let reservation = Reservation {
+            reservation_id: "r1".to_string(),
+            requester_cd_id: "cd_alpha".to_string(),
+            product: product(0),
+            quantity: 5,
+            status: ReservationStatus::Committed,
+            created_at: Utc::now(),
+            expires_at: Utc::now(),
+        };
+
+        assert!(credit_inbound(&mut inventory, &pending, &reservation).unwrap());
+        assert!(!credit_inbound(&mut inventory, &pending, &reservation).unwrap());
+        assert_eq!(inventory.get("P001").unwrap().quantity, Some(15));
+        assert!(was_credited(&inventory, "r1"));
+        assert!(inventory.finish_inbound("r1", None).is_err());
+
+        drop(inventory);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn available(cd_id: &str, quantity_available: u32) -> ProductAvailability {
+        ProductAvailability {
+            cd_id: cd_id.to_string(),
+            quantity_available,
+            product_info: product(quantity_available),
+        }
+    }
+
+    #[test]
+    fn plan_takes_from_the_largest_stocks_first() {
+        let cds = vec![
+            available("cd_beta", 10),
+            available("cd_gamma", 40),
+            available("cd_delta", 25),
+        ];
+        let plan = plan_allocation("cd_alpha", cds, 50);
+        assert_eq!(
+            plan,
+            vec![("cd_gamma".to_string(), 40), ("cd_delta".to_string(), 10)]
+        );
+    }
+
+    #[test]
+    fn plan_uses_a_single_cd_when_it_has_enough() {
+        let cds = vec![available("cd_beta", 10), available("cd_gamma", 40)];
+        let plan = plan_allocation("cd_alpha", cds, 30);
+        assert_eq!(plan, vec![("cd_gamma".to_string(), 30)]);
+    }
+
+    #[test]
+    fn plan_skips_own_cd_and_empty_stocks() {
+        let cds = vec![
+            available("cd_alpha", 100),
+            available("cd_beta", 0),
+            available("cd_gamma", 5),
+        ];
+        let plan = plan_allocation("cd_alpha", cds, 5);
+        assert_eq!(plan, vec![("cd_gamma".to_string(), 5)]);
+    }
+
+    #[test]
+    fn plan_falls_short_when_total_stock_is_insufficient() {
+        let cds = vec![available("cd_beta", 10), available("cd_gamma", 15)];
+        let plan = plan_allocation("cd_alpha", cds, 40);
+        assert_eq!(
+            plan,
+            vec![("cd_gamma".to_string(), 15), ("cd_beta".to_string(), 10)]
+        );
+        assert_eq!(plan.iter().map(|(_, q)| q).sum::<u32>(), 25);
+    }
+}
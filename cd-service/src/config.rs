@@ -0,0 +1,436 @@
+use common_models::{validate_tags, Product, ReorderPolicy};
+use serde::Deserialize;
+use service_auth::TlsFiles;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const CONFIG_ENV_VAR: &str = "CD_CONFIG";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    id: String,
+    bind_address: String,
+    advertised_address: Option<String>,
+    discovery_url: Option<String>,
+    #[serde(default)]
+    discovery_urls: Vec<String>,
+    hub_url: String,
+    #[serde(default = "default_heartbeat_interval_secs")]
+    heartbeat_interval_secs: u64,
+    data_dir: Option<PathBuf>,
+    #[serde(default)]
+    inventory: Vec<Product>,
+    #[serde(default = "default_replenish_interval_secs")]
+    replenish_interval_secs: u64,
+    #[serde(default = "default_replenish_cooldown_secs")]
+    replenish_cooldown_secs: u64,
+    #[serde(default)]
+    reorder_policies: Vec<ReorderPolicy>,
+    tls: Option<TlsFiles>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    10
+}
+
+fn default_replenish_interval_secs() -> u64 {
+    5
+}
+
+fn default_replenish_cooldown_secs() -> u64 {
+    60
+}
+
+/// Validated settings for one distribution center.
+#[derive(Debug)]
+pub struct CdConfig {
+    pub id: String,
+    pub bind_address: SocketAddr,
+    pub advertised_ip: String,
+    pub advertised_port: u16,
+    pub discovery_urls: Vec<String>,
+    pub hub_url: String,
+    pub heartbeat_interval: Duration,
+    pub data_dir: PathBuf,
+    pub inventory: Vec<Product>,
+    /// How often stock is checked against the reorder policies.
+    pub replenish_interval: Duration,
+    /// Cooldown of policies that do not set their own.
+    pub replenish_cooldown: Duration,
+    pub reorder_policies: Vec<ReorderPolicy>,
+    /// Serves HTTPS and presents this certificate to other services when set.
+    pub tls: Option<TlsFiles>,
+    /// Registered with Service Discovery, to select CDs by region, capacity...
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    MissingPath,
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingPath => write!(
+                f,
+                "no config file given; pass --config <path> or set {}",
+                CONFIG_ENV_VAR
+            ),
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::Invalid(path, problems) => {
+                writeln!(f, "invalid config {}:", path.display())?;
+                for problem in problems {
+                    writeln!(f, "  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl CdConfig {
+    /// Loads the config from `--config <path>` or, failing that, `CD_CONFIG`.
+    pub fn from_args(args: &[String]) -> Result<Self, ConfigError> {
+        let path = args
+            .iter()
+            .position(|arg| arg == "--config")
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+            .or_else(|| std::env::var(CONFIG_ENV_VAR).ok())
+            .map(PathBuf::from)
+            .ok_or(ConfigError::MissingPath)?;
+        Self::load(path)
+    }
+
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        let raw: RawConfig =
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+        raw.validate()
+            .map_err(|problems| ConfigError::Invalid(path, problems))
+    }
+}
+
+impl RawConfig {
+    fn validate(self) -> Result<CdConfig, Vec<String>> {
+        let mut problems = Vec::new();
+
+        if self.id.trim().is_empty() {
+            problems.push("id must not be empty".to_string());
+        }
+
+        let bind_address = self
+            .bind_address
+            .parse::<SocketAddr>()
+            .map_err(|e| problems.push(format!("bind_address {:?}: {}", self.bind_address, e)))
+            .ok();
+
+        let advertised = match &self.advertised_address {
+            Some(address) => match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() => match port.parse::<u16>() {
+                    Ok(port) => Some((host.to_string(), port)),
+                    Err(e) => {
+                        problems.push(format!("advertised_address {:?}: {}", address, e));
+                        None
+                    }
+                },
+                _ => {
+                    problems.push(format!(
+                        "advertised_address {:?} must have the form host:port",
+                        address
+                    ));
+                    None
+                }
+            },
+            None => bind_address.map(|addr| (addr.ip().to_string(), addr.port())),
+        };
+        if let Some((host, _)) = &advertised {
+            if host == "0.0.0.0" || host == "::" {
+                problems.push(
+                    "advertised_address is required when bind_address is a wildcard address"
+                        .to_string(),
+                );
+            }
+        }
+
+        // `discovery_url` é a forma curta para uma única réplica.
+        let discovery_urls: Vec<String> = self
+            .discovery_url
+            .iter()
+            .chain(&self.discovery_urls)
+            .map(|url| url.trim_end_matches('/').to_string())
+            .collect();
+        if discovery_urls.is_empty() {
+            problems.push("discovery_url or discovery_urls is required".to_string());
+        }
+
+        let mut urls = vec![("hub_url".to_string(), &self.hub_url)];
+        if let Some(url) = &self.discovery_url {
+            urls.push(("discovery_url".to_string(), url));
+        }
+        for (i, url) in self.discovery_urls.iter().enumerate() {
+            urls.push((format!("discovery_urls[{}]", i), url));
+        }
+        for (field, url) in urls {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                problems.push(format!(
+                    "{} {:?} must start with http:// or https://",
+                    field, url
+                ));
+            }
+        }
+
+        problems.extend(validate_tags(&self.tags));
+
+        if self.heartbeat_interval_secs == 0 {
+            problems.push("heartbeat_interval_secs must be greater than zero".to_string());
+        }
+
+        let mut seen_codes = HashSet::new();
+        for (i, product) in self.inventory.iter().enumerate() {
+            if product.code.trim().is_empty() {
+                problems.push(format!("inventory[{}]: code must not be empty", i));
+            } else if !seen_codes.insert(product.code.as_str()) {
+                problems.push(format!(
+                    "inventory[{}]: duplicate product code {:?}",
+                    i, product.code
+                ));
+            }
+            if let Err(problem) = product.price.validate_price() {
+                problems.push(format!(
+                    "inventory[{}]: {} for {:?}",
+                    i, problem, product.code
+                ));
+            }
+            if product.quantity.is_none() {
+                problems.push(format!(
+                    "inventory[{}]: quantity of {:?} is required",
+                    i, product.code
+                ));
+            }
+        }
+
+        if self.replenish_interval_secs == 0 {
+            problems.push("replenish_interval_secs must be greater than zero".to_string());
+        }
+
+        let mut seen_policies = HashSet::new();
+        for (i, policy) in self.reorder_policies.iter().enumerate() {
+            if !seen_policies.insert(policy.product_code.as_str()) {
+                problems.push(format!(
+                    "reorder_policies[{}]: duplicate product code {:?}",
+                    i, policy.product_code
+                ));
+            }
+            problems.extend(
+                validate_reorder_policy(policy)
+                    .into_iter()
+                    .map(|problem| format!("reorder_policies[{}]: {}", i, problem)),
+            );
+        }
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let (advertised_ip, advertised_port) = advertised.unwrap();
+        Ok(CdConfig {
+            data_dir: self
+                .data_dir
+                .unwrap_or_else(|| PathBuf::from("data").join(&self.id)),
+            id: self.id,
+            bind_address: bind_address.unwrap(),
+            advertised_ip,
+            advertised_port,
+            discovery_urls,
+            hub_url: self.hub_url.trim_end_matches('/').to_string(),
+            heartbeat_interval: Duration::from_secs(self.heartbeat_interval_secs),
+            inventory: self.inventory,
+            replenish_interval: Duration::from_secs(self.replenish_interval_secs),
+            replenish_cooldown: Duration::from_secs(self.replenish_cooldown_secs),
+            reorder_policies: self.reorder_policies,
+            tls: self.tls,
+            tags: self.tags,
+            metadata: self.metadata,
+        })
+    }
+}
+
+/// Problems with a reorder policy, shared by the config file and
+/// `PUT /reorder_policies/{product_code}`.
+pub fn validate_reorder_policy(policy: &ReorderPolicy) -> Vec<String> {
+    let mut problems = Vec::new();
+    if policy.product_code.trim().is_empty() {
+        problems.push("product_code must not be empty".to_string());
+    }
+    if policy.target_quantity == 0 {
+        problems.push(format!(
+            "target_quantity of {:?} must be greater than zero",
+            policy.product_code
+        ));
+    }
+    if policy.min_quantity > policy.target_quantity {
+        problems.push(format!(
+            "min_quantity of {:?} must not be greater than target_quantity",
+            policy.product_code
+        ));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        id = "cd_test"
+        bind_address = "127.0.0.1:9000"
+        discovery_url = "http://127.0.0.1:8080/"
+        hub_url = "http://127.0.0.1:8082/"
+    "#;
+
+    fn parse(toml: &str) -> Result<CdConfig, Vec<String>> {
+        toml::from_str::<RawConfig>(toml).unwrap().validate()
+    }
+
+    fn problems(toml: &str) -> Vec<String> {
+        parse(toml).unwrap_err()
+    }
+
+    fn policy(product_code: &str, min_quantity: u32, target_quantity: u32) -> ReorderPolicy {
+        ReorderPolicy {
+            product_code: product_code.to_string(),
+            min_quantity,
+            target_quantity,
+            cooldown_secs: None,
+        }
+    }
+
+    #[test]
+    fn shipped_configs_are_valid() {
+        for toml in [
+            include_str!("../../config/cd_alpha.toml"),
+            include_str!("../../config/cd_beta.toml"),
+            include_str!("../../config/cd_gamma.toml"),
+        ] {
+            parse(toml).unwrap();
+        }
+    }
+
+    #[test]
+    fn minimal_config_gets_defaults() {
+        let config = parse(MINIMAL).unwrap();
+        assert_eq!(config.advertised_ip, "127.0.0.1");
+        assert_eq!(config.advertised_port, 9000);
+        assert_eq!(config.discovery_urls, vec!["http://127.0.0.1:8080"]);
+        assert_eq!(config.hub_url, "http://127.0.0.1:8082");
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(10));
+        assert_eq!(config.replenish_cooldown, Duration::from_secs(60));
+        assert_eq!(config.data_dir, PathBuf::from("data").join("cd_test"));
+        assert!(config.inventory.is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let toml = format!("{}\nheartbeat_secs = 5\n", MINIMAL);
+        let error = toml::from_str::<RawConfig>(&toml).unwrap_err();
+        assert!(error.to_string().contains("heartbeat_secs"));
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let problems = problems(
+            r#"
+            id = " "
+            bind_address = "0.0.0.0:9000"
+            hub_url = "127.0.0.1:8082"
+            heartbeat_interval_secs = 0
+            "#,
+        );
+        assert_eq!(
+            problems,
+            vec![
+                "id must not be empty",
+                "advertised_address is required when bind_address is a wildcard address",
+                "discovery_url or discovery_urls is required",
+                "hub_url \"127.0.0.1:8082\" must start with http:// or https://",
+                "heartbeat_interval_secs must be greater than zero",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_product_codes_are_rejected() {
+        let toml = format!(
+            r#"{}
+            [[inventory]]
+            code = "garrafas"
+            name = "Garrafas"
+            price = 2.50
+            quantity = 50
+
+            [[inventory]]
+            code = "garrafas"
+            name = "Outras garrafas"
+            price = 3.00
+            "#,
+            MINIMAL
+        );
+        assert_eq!(
+            problems(&toml),
+            vec![
+                "inventory[1]: duplicate product code \"garrafas\"",
+                "inventory[1]: quantity of \"garrafas\" is required",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_reorder_policies_are_rejected() {
+        let toml = format!(
+            r#"{}
+            [[reorder_policies]]
+            product_code = "celulares"
+            min_quantity = 20
+            target_quantity = 10
+
+            [[reorder_policies]]
+            product_code = "celulares"
+            min_quantity = 0
+            target_quantity = 0
+            "#,
+            MINIMAL
+        );
+        assert_eq!(
+            problems(&toml),
+            vec![
+                "reorder_policies[0]: min_quantity of \"celulares\" must not be greater than target_quantity",
+                "reorder_policies[1]: duplicate product code \"celulares\"",
+                "reorder_policies[1]: target_quantity of \"celulares\" must be greater than zero",
+            ]
+        );
+    }
+
+    #[test]
+    fn reorder_policy_validation() {
+        assert!(validate_reorder_policy(&policy("celulares", 5, 10)).is_empty());
+        assert!(validate_reorder_policy(&policy("celulares", 10, 10)).is_empty());
+        assert_eq!(validate_reorder_policy(&policy(" ", 0, 1)).len(), 1);
+        assert_eq!(validate_reorder_policy(&policy("celulares", 1, 0)).len(), 2);
+    }
+}
@@ -1,7 +1,85 @@
 // cd-service/src/handlers.rs
+use super::client;
+use super::config;
+use super::metrics::Direction;
+use super::replenish;
 use super::state::CdState;
-use actix_web::{web, HttpResponse, Responder};
-use common_models::{Product, TransferRequest};
+use super::storage::{InventoryStore, ProcessedRequest, StockChange};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use chrono::{DateTime, Utc};
+use common_models::{
+    ApiError, ErrorCode, MovementReason, Product, ProductRequest, ProductRequestState,
+    ProductRequestStatus, ProductValuation, ReceiveRequest, ReorderLevels, ReorderPolicy,
+    ReorderPolicyStatus, Reservation, ReservationRequest, ReservationStatus, StockMovement,
+    TransferRequest, ValuationQuery, ValuationReport,
+};
+use serde::Deserialize;
+use service_auth::{Caller, Role};
+use std::sync::atomic::Ordering;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info};
+
+// GET /inventory: Todo o inventário do CD, ordenado por código.
+pub async fn list_inventory(data: web::Data<CdState>) -> impl Responder {
+    let inventory = data.inventory.lock().unwrap();
+    let mut products: Vec<&Product> = inventory.products().collect();
+    products.sort_by(|a, b| a.code.cmp(&b.code));
+    HttpResponse::Ok().json(products)
+}
+
+// GET /metrics: Métricas no formato do Prometheus.
+pub async fn get_metrics(data: web::Data<CdState>) -> impl Responder {
+    {
+        let inventory = data.inventory.lock().unwrap();
+        // Produtos que saíram do inventário não devem continuar na exportação.
+        data.metrics.stock_quantity.reset();
+        for product in inventory.products() {
+            data.metrics
+                .stock_quantity
+                .with_label_values(&[&product.code])
+                .set(i64::from(product.quantity.unwrap_or(0)));
+        }
+    }
+    telemetry::metrics_response(&data.metrics.registry)
+}
+
+// GET /stock_snapshot: Inventário completo e o último evento de estoque que ele reflete,
+// usado pelo Hub para ressincronizar.
+pub async fn get_stock_snapshot(data: web::Data<CdState>) -> impl Responder {
+    let inventory = data.inventory.lock().unwrap();
+    HttpResponse::Ok().json(client::stock_snapshot(&data.own_id, &inventory))
+}
+
+// GET /reports/valuation: Valor do estoque deste CD, por produto, em JSON ou CSV.
+pub async fn get_valuation_report(
+    query: web::Query<ValuationQuery>,
+    data: web::Data<CdState>,
+) -> impl Responder {
+    let report = data
+        .inventory
+        .lock()
+        .unwrap()
+        .products()
+        .map(|p| {
+            ProductValuation::new(
+                p.code.clone(),
+                p.name.clone(),
+                u64::from(p.quantity.unwrap_or(0)),
+                p.price,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|products| ValuationReport::new(Some(data.own_id.clone()), products, &query));
+    match report {
+        Ok(report) => report.respond(query.format),
+        Err(e) => ApiError::new(
+            ErrorCode::Internal,
+            format!("Cannot value the stock: {}", e),
+        )
+        .error_response(),
+    }
+}
 
 // GET /inventory/{product_code}: Retorna a quantidade e detalhes de um produto no inventário local.
 pub async fn get_product_inventory(
@@ -14,66 +92,864 @@ pub async fn get_product_inventory(
     if let Some(product) = inventory.get(&product_code) {
         HttpResponse::Ok().json(product)
     } else {
-        HttpResponse::NotFound().body(format!("Product {} not found in this CD", product_code))
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("Product {} not found in this CD", product_code),
+        )
+        .error_response()
     }
 }
 
+#[derive(Deserialize)]
+pub struct LedgerQuery {
+    pub product_code: Option<String>,
+    pub counterparty: Option<String>,
+    pub reason: Option<MovementReason>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+// GET /ledger: Lista as movimentações de estoque, filtrando por produto, CD de contrapartida,
+// motivo e intervalo de tempo (from inclusivo, to exclusivo, em RFC 3339).
+pub async fn get_ledger(
+    query: web::Query<LedgerQuery>,
+    data: web::Data<CdState>,
+) -> impl Responder {
+    let inventory = data.inventory.lock().unwrap();
+    let movements: Vec<&StockMovement> = inventory
+        .ledger()
+        .iter()
+        .filter(|m| {
+            query
+                .product_code
+                .as_ref()
+                .is_none_or(|code| &m.product_code == code)
+                && query
+                    .counterparty
+                    .as_ref()
+                    .is_none_or(|cd| m.counterparty_cd_id.as_ref() == Some(cd))
+                && query.reason.is_none_or(|reason| m.reason == reason)
+                && query.from.is_none_or(|from| m.timestamp >= from)
+                && query.to.is_none_or(|to| m.timestamp < to)
+        })
+        .collect();
+    HttpResponse::Ok().json(movements)
+}
+
 // POST /transfer_product: Recebe um pedido de transferência de outro CD.
 pub async fn transfer_product(
     transfer_req: web::Json<TransferRequest>,
     data: web::Data<CdState>,
+    caller: Caller,
 ) -> impl Responder {
+    if let Err(e) = caller.require_cd(&transfer_req.requester_cd_id) {
+        return e.error_response();
+    }
     let mut inventory = data.inventory.lock().unwrap();
-    if let Some(product) = inventory.get_mut(&transfer_req.product_code) {
+    let fingerprint = serde_json::to_string(&*transfer_req).unwrap_or_default();
+    if let Some(response) =
+        replay_processed(&inventory, transfer_req.request_id.as_deref(), &fingerprint)
+    {
+        return response;
+    }
+
+    if let Some(product) = inventory.get(&transfer_req.product_code) {
         // CORREÇÃO: Acessar a quantidade usando .unwrap_or(0) ou match
         // Como estamos lidando com um inventário, esperamos que quantity seja Some(u32)
         let current_quantity = product.quantity.unwrap_or(0); // Assume 0 se for None, o que não deveria acontecer no inventário do CD
         if current_quantity >= transfer_req.quantity {
-            product.quantity = Some(current_quantity - transfer_req.quantity);
-            println!(
-                "[{}] Transferred {} of {} to {}",
-                data.own_id,
-                transfer_req.quantity,
-                transfer_req.product_code,
-                transfer_req.requester_cd_id
+            let mut updated = product.clone();
+            updated.quantity = Some(current_quantity - transfer_req.quantity);
+            let body = "Transfer successful";
+            let processed =
+                processed_request(transfer_req.request_id.as_deref(), fingerprint, body);
+            let change = StockChange {
+                product: updated,
+                reason: MovementReason::TransferOut,
+                counterparty_cd_id: Some(transfer_req.requester_cd_id.clone()),
+                request_id: transfer_req.request_id.clone(),
+            };
+            if let Err(e) = inventory.upsert(change, processed) {
+                error!("Failed to persist transfer: {}", e);
+                return ApiError::new(ErrorCode::Internal, "Failed to persist inventory change")
+                    .error_response();
+            }
+            data.metrics
+                .record_transfer(Direction::Out, transfer_req.quantity);
+            info!(
+                "Transferred {} of {} to {}",
+                transfer_req.quantity, transfer_req.product_code, transfer_req.requester_cd_id
             );
-            HttpResponse::Ok().body("Transfer successful")
+            HttpResponse::Ok().body(body)
         } else {
-            HttpResponse::BadRequest().body(format!(
-                "Not enough quantity of {} in {} for transfer. Has {}, requested {}",
-                transfer_req.product_code, data.own_id, current_quantity, transfer_req.quantity
-            ))
+            ApiError::new(
+                ErrorCode::InsufficientStock,
+                format!(
+                    "Not enough quantity of {} in {} for transfer. Has {}, requested {}",
+                    transfer_req.product_code, data.own_id, current_quantity, transfer_req.quantity
+                ),
+            )
+            .error_response()
         }
     } else {
-        HttpResponse::NotFound().body(format!(
-            "Product {} not found in {} for transfer",
-            transfer_req.product_code, data.own_id
-        ))
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!(
+                "Product {} not found in {} for transfer",
+                transfer_req.product_code, data.own_id
+            ),
+        )
+        .error_response()
     }
 }
 
 // POST /receive_product: Recebe produtos de outro CD (simplesmente adiciona ao inventário).
 pub async fn receive_product(
-    product_data: web::Json<Product>,
+    receive_req: web::Json<ReceiveRequest>,
     data: web::Data<CdState>,
+    caller: Caller,
 ) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Cd]) {
+        return e.error_response();
+    }
     let mut inventory = data.inventory.lock().unwrap();
+    let fingerprint = serde_json::to_string(&*receive_req).unwrap_or_default();
+    if let Some(response) =
+        replay_processed(&inventory, receive_req.request_id.as_deref(), &fingerprint)
+    {
+        return response;
+    }
+
+    let product_data = &receive_req.product;
     let product_code = product_data.code.clone();
     let quantity_received = product_data.quantity.unwrap_or(0); // CORREÇÃO: Tratar Option<u32>
 
-    inventory
-        .entry(product_code.clone())
-        .and_modify(|p| p.quantity = Some(p.quantity.unwrap_or(0) + quantity_received)) // CORREÇÃO
-        .or_insert(Product {
+    let updated = match inventory.get(&product_code) {
+        Some(p) => {
+            let Some(quantity) = p.quantity.unwrap_or(0).checked_add(quantity_received) else {
+                return ApiError::new(
+                    ErrorCode::Conflict,
+                    format!(
+                        "Receiving {} of {} would exceed the largest stock a CD can hold",
+                        quantity_received, product_code
+                    ),
+                )
+                .error_response();
+            };
+            Product {
+                quantity: Some(quantity),
+                ..p.clone()
+            }
+        }
+        None => Product {
             code: product_data.code.clone(),
             name: product_data.name.clone(),
             price: product_data.price,
             quantity: Some(quantity_received),
+        },
+    };
+    let body = "Product received successfully";
+    let processed = processed_request(receive_req.request_id.as_deref(), fingerprint, body);
+    let change = StockChange {
+        product: updated,
+        reason: MovementReason::Receive,
+        counterparty_cd_id: None,
+        request_id: receive_req.request_id.clone(),
+    };
+    if let Err(e) = inventory.upsert(change, processed) {
+        error!("Failed to persist received product: {}", e);
+        return ApiError::new(ErrorCode::Internal, "Failed to persist inventory change")
+            .error_response();
+    }
+
+    data.metrics
+        .record_transfer(Direction::In, quantity_received);
+    info!("Received {} of {}", quantity_received, product_code);
+    HttpResponse::Ok().body(body)
+}
+
+// POST /requests: Inicia, em segundo plano, a busca de um produto nos outros CDs.
+pub async fn create_product_request(
+    product_req: web::Json<ProductRequest>,
+    data: web::Data<CdState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Operator]) {
+        return e.error_response();
+    }
+    if data.shutting_down.load(Ordering::SeqCst) {
+        return shutting_down();
+    }
+    if product_req.product_code.trim().is_empty() || product_req.quantity == 0 {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "A product request needs a product_code and a quantity greater than zero",
+        )
+        .error_response();
+    }
+
+    let status = client::start_product_request(data.clone(), product_req.into_inner());
+    info!(
+        "Accepted request {} for {} of {}",
+        status.request_id, status.quantity, status.product_code
+    );
+    HttpResponse::Accepted()
+        .insert_header(("Location", format!("/requests/{}", status.request_id)))
+        .json(status)
+}
+
+// GET /requests/{request_id}: Consulta o andamento e o resultado de um pedido.
+pub async fn get_product_request(
+    path: web::Path<String>,
+    data: web::Data<CdState>,
+) -> impl Responder {
+    let request_id = path.into_inner();
+    let requests = data.product_requests.lock().unwrap();
+
+    match requests.get(&request_id) {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => ApiError::new(
+            ErrorCode::NotFound,
+            format!("Request {} not found", request_id),
+        )
+        .error_response(),
+    }
+}
+
+// GET /requests: Lista os pedidos deste CD, do mais recente para o mais antigo.
+pub async fn list_product_requests(data: web::Data<CdState>) -> impl Responder {
+    let mut requests: Vec<ProductRequestStatus> = data
+        .product_requests
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect();
+    requests.sort_by_key(|r| std::cmp::Reverse(r.created_at));
+    HttpResponse::Ok().json(requests)
+}
+
+// GET /reorder_policies: Políticas de reposição com o estoque atual e o estado da reposição.
+pub async fn list_reorder_policies(data: web::Data<CdState>) -> impl Responder {
+    let policies: Vec<ReorderPolicy> = data
+        .inventory
+        .lock()
+        .unwrap()
+        .reorder_policies()
+        .cloned()
+        .collect();
+    let mut statuses: Vec<ReorderPolicyStatus> = policies
+        .into_iter()
+        .map(|policy| replenish::policy_status(&data, policy))
+        .collect();
+    statuses.sort_by(|a, b| a.policy.product_code.cmp(&b.policy.product_code));
+    HttpResponse::Ok().json(statuses)
+}
+
+// GET /reorder_policies/{product_code}
+pub async fn get_reorder_policy(
+    path: web::Path<String>,
+    data: web::Data<CdState>,
+) -> impl Responder {
+    let product_code = path.into_inner();
+    let policy = data
+        .inventory
+        .lock()
+        .unwrap()
+        .reorder_policy(&product_code)
+        .cloned();
+    match policy {
+        Some(policy) => HttpResponse::Ok().json(replenish::policy_status(&data, policy)),
+        None => reorder_policy_not_found(&product_code).error_response(),
+    }
+}
+
+// PUT /reorder_policies/{product_code}: Cria ou substitui a política de reposição de um produto.
+pub async fn put_reorder_policy(
+    path: web::Path<String>,
+    levels: web::Json<ReorderLevels>,
+    data: web::Data<CdState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Operator]) {
+        return e.error_response();
+    }
+    let levels = levels.into_inner();
+    let policy = ReorderPolicy {
+        product_code: path.into_inner(),
+        min_quantity: levels.min_quantity,
+        target_quantity: levels.target_quantity,
+        cooldown_secs: levels.cooldown_secs,
+    };
+    let problems = config::validate_reorder_policy(&policy);
+    if !problems.is_empty() {
+        return ApiError::new(ErrorCode::BadRequest, problems.join("; ")).error_response();
+    }
+
+    if let Err(e) = data
+        .inventory
+        .lock()
+        .unwrap()
+        .put_reorder_policy(policy.clone())
+    {
+        return ApiError::new(
+            ErrorCode::Internal,
+            format!("Failed to persist reorder policy: {}", e),
+        )
+        .error_response();
+    }
+    info!(
+        "Reorder policy for {}: min {}, target {}",
+        policy.product_code, policy.min_quantity, policy.target_quantity
+    );
+    HttpResponse::Ok().json(replenish::policy_status(&data, policy))
+}
+
+// DELETE /reorder_policies/{product_code}: Desliga a reposição automática do produto.
+// Uma reposição já em andamento não é interrompida.
+pub async fn delete_reorder_policy(
+    path: web::Path<String>,
+    data: web::Data<CdState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Operator]) {
+        return e.error_response();
+    }
+    let product_code = path.into_inner();
+    let mut inventory = data.inventory.lock().unwrap();
+    if inventory.reorder_policy(&product_code).is_none() {
+        return reorder_policy_not_found(&product_code).error_response();
+    }
+    if let Err(e) = inventory.remove_reorder_policy(&product_code) {
+        return ApiError::new(
+            ErrorCode::Internal,
+            format!("Failed to persist reorder policy removal: {}", e),
+        )
+        .error_response();
+    }
+    HttpResponse::NoContent().finish()
+}
+
+fn reorder_policy_not_found(product_code: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        format!("No reorder policy for {}", product_code),
+    )
+}
+
+const PROCESSED_REQUEST_RETENTION_HOURS: i64 = 24;
+
+/// Answers a retried request with the response stored for its id. A reused id
+/// with a different payload is rejected instead of being applied again.
+///
+/// Only successes are stored (see `processed_request`): a request that failed,
+/// e.g. for lack of stock, leaves nothing behind and its retry runs again, so
+/// it may succeed once the stock is there.
+fn replay_processed(
+    inventory: &InventoryStore,
+    request_id: Option<&str>,
+    fingerprint: &str,
+) -> Option<HttpResponse> {
+    let processed = inventory.processed_request(request_id?)?;
+    if processed.fingerprint != fingerprint {
+        return Some(
+            ApiError::new(
+                ErrorCode::Conflict,
+                format!(
+                    "Request id {} was already used for a different request",
+                    processed.request_id
+                ),
+            )
+            .error_response(),
+        );
+    }
+    let status = StatusCode::from_u16(processed.status).unwrap_or(StatusCode::OK);
+    Some(
+        HttpResponse::build(status)
+            .insert_header(("Idempotent-Replayed", "true"))
+            .body(processed.body.clone()),
+    )
+}
+
+fn processed_request(
+    request_id: Option<&str>,
+    fingerprint: String,
+    body: &str,
+) -> Option<ProcessedRequest> {
+    Some(ProcessedRequest {
+        request_id: request_id?.to_string(),
+        fingerprint,
+        status: StatusCode::OK.as_u16(),
+        body: body.to_string(),
+        processed_at: Utc::now(),
+    })
+}
+
+pub async fn expire_processed_requests(state: web::Data<CdState>) {
+    let check_interval = Duration::from_secs(60);
+    let retention = chrono::Duration::hours(PROCESSED_REQUEST_RETENTION_HOURS);
+
+    loop {
+        sleep(check_interval).await;
+        let mut inventory = state.inventory.lock().unwrap();
+        let now = Utc::now();
+        let expired: Vec<String> = inventory
+            .processed_requests()
+            .filter(|p| now - p.processed_at > retention)
+            .map(|p| p.request_id.clone())
+            .collect();
+        if let Err(e) = inventory.remove_processed(expired) {
+            error!("Failed to prune processed requests: {}", e);
+        }
+    }
+}
+
+const PRODUCT_REQUEST_RETENTION_HOURS: i64 = 24;
+
+/// Forgets product requests that finished long ago, so `GET /requests` and
+/// the memory it uses do not grow forever. Pending and running requests stay.
+pub async fn expire_product_requests(state: web::Data<CdState>) {
+    let check_interval = Duration::from_secs(60);
+    let retention = chrono::Duration::hours(PRODUCT_REQUEST_RETENTION_HOURS);
+
+    loop {
+        sleep(check_interval).await;
+        let now = Utc::now();
+        state.product_requests.lock().unwrap().retain(|_, r| {
+            matches!(
+                r.state,
+                ProductRequestState::Pending | ProductRequestState::Running
+            ) || now - r.updated_at <= retention
         });
+    }
+}
 
-    println!(
-        "[{}] Received {} of {}",
-        data.own_id, quantity_received, product_code
+/// Answers a retried reservation with the reservation it created, like
+/// `replay_processed` does for other requests: only when the retry asks for
+/// the same thing, so a reused id cannot hand over someone else's reservation.
+fn replay_reservation(existing: &Reservation, request: &ReservationRequest) -> HttpResponse {
+    let fingerprint = |product_code: &str, quantity: u32, requester_cd_id: &str| {
+        (
+            product_code.to_string(),
+            quantity,
+            requester_cd_id.to_string(),
+        )
+    };
+    let stored = fingerprint(
+        &existing.product.code,
+        existing.quantity,
+        &existing.requester_cd_id,
+    );
+    let retried = fingerprint(
+        &request.product_code,
+        request.quantity,
+        &request.requester_cd_id,
     );
-    HttpResponse::Ok().body("Product received successfully")
+    if stored != retried {
+        return ApiError::new(
+            ErrorCode::Conflict,
+            format!(
+                "Reservation id {} was already used for a different reservation",
+                existing.reservation_id
+            ),
+        )
+        .error_response();
+    }
+    HttpResponse::Ok()
+        .insert_header(("Idempotent-Replayed", "true"))
+        .json(existing)
+}
+
+const MAX_RESERVATION_TTL_SECS: u64 = 300;
+const RESERVATION_RETENTION_DAYS: i64 = 7;
+
+// POST /reservations: Separa estoque para outro CD até o commit, o abort ou o fim do prazo.
+pub async fn reserve_product(
+    reservation_req: web::Json<ReservationRequest>,
+    data: web::Data<CdState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require_cd(&reservation_req.requester_cd_id) {
+        return e.error_response();
+    }
+    let mut inventory = data.inventory.lock().unwrap();
+
+    // Um retry do mesmo pedido devolve a reserva já criada.
+    if let Some(existing) = inventory.reservation(&reservation_req.reservation_id) {
+        return replay_reservation(existing, &reservation_req);
+    }
+
+    if data.shutting_down.load(Ordering::SeqCst) {
+        return shutting_down();
+    }
+
+    if reservation_req.quantity == 0 {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "Reservation quantity must be greater than zero",
+        )
+        .error_response();
+    }
+
+    let Some(product) = inventory.get(&reservation_req.product_code).cloned() else {
+        return ApiError::new(
+            ErrorCode::NotFound,
+            format!(
+                "Product {} not found in {} for reservation",
+                reservation_req.product_code, data.own_id
+            ),
+        )
+        .error_response();
+    };
+
+    let current_quantity = product.quantity.unwrap_or(0);
+    if current_quantity < reservation_req.quantity {
+        return ApiError::new(
+            ErrorCode::InsufficientStock,
+            format!(
+                "Not enough quantity of {} in {} for reservation. Has {}, requested {}",
+                reservation_req.product_code,
+                data.own_id,
+                current_quantity,
+                reservation_req.quantity
+            ),
+        )
+        .error_response();
+    }
+
+    let now = Utc::now();
+    let ttl_secs = reservation_req.ttl_secs.clamp(1, MAX_RESERVATION_TTL_SECS);
+    let reservation = Reservation {
+        reservation_id: reservation_req.reservation_id.clone(),
+        requester_cd_id: reservation_req.requester_cd_id.clone(),
+        product: Product {
+            quantity: None,
+            ..product.clone()
+        },
+        quantity: reservation_req.quantity,
+        status: ReservationStatus::Reserved,
+        created_at: now,
+        expires_at: now + chrono::Duration::seconds(ttl_secs as i64),
+    };
+    let updated = Product {
+        quantity: Some(current_quantity - reservation_req.quantity),
+        ..product
+    };
+
+    let change = StockChange {
+        product: updated,
+        reason: MovementReason::ReservationHold,
+        counterparty_cd_id: Some(reservation.requester_cd_id.clone()),
+        request_id: Some(reservation.reservation_id.clone()),
+    };
+    if let Err(e) = inventory.put_reservation(reservation.clone(), Some(change)) {
+        error!("Failed to persist reservation: {}", e);
+        return ApiError::new(ErrorCode::Internal, "Failed to persist reservation")
+            .error_response();
+    }
+
+    info!(
+        "Reserved {} of {} for {} ({}), expires at {}",
+        reservation.quantity,
+        reservation.product.code,
+        reservation.requester_cd_id,
+        reservation.reservation_id,
+        reservation.expires_at
+    );
+    HttpResponse::Ok().json(reservation)
+}
+
+// GET /reservations/{reservation_id}: Consulta o estado de uma reserva.
+pub async fn get_reservation(path: web::Path<String>, data: web::Data<CdState>) -> impl Responder {
+    let reservation_id = path.into_inner();
+    let inventory = data.inventory.lock().unwrap();
+
+    match inventory.reservation(&reservation_id) {
+        Some(reservation) => HttpResponse::Ok().json(reservation),
+        None => reservation_not_found(&reservation_id).error_response(),
+    }
+}
+
+// POST /reservations/{reservation_id}/commit: Confirma a saída do estoque reservado.
+pub async fn commit_reservation(
+    path: web::Path<String>,
+    data: web::Data<CdState>,
+    caller: Caller,
+) -> impl Responder {
+    let reservation_id = path.into_inner();
+    let mut inventory = data.inventory.lock().unwrap();
+
+    let Some(reservation) = inventory.reservation(&reservation_id).cloned() else {
+        return reservation_not_found(&reservation_id).error_response();
+    };
+    if let Err(e) = caller.require_cd(&reservation.requester_cd_id) {
+        return e.error_response();
+    }
+
+    match reservation.status {
+        // Commit repetido (resposta anterior perdida): devolve o mesmo resultado.
+        ReservationStatus::Committed => HttpResponse::Ok().json(reservation),
+        ReservationStatus::Reserved if reservation.expires_at > Utc::now() => {
+            let committed = Reservation {
+                status: ReservationStatus::Committed,
+                ..reservation
+            };
+            if let Err(e) = inventory.commit_reservation(committed.clone()) {
+                error!("Failed to persist commit: {}", e);
+                return ApiError::new(ErrorCode::Internal, "Failed to persist commit")
+                    .error_response();
+            }
+            data.metrics
+                .record_transfer(Direction::Out, committed.quantity);
+            info!(
+                "Committed transfer of {} of {} to {} ({})",
+                committed.quantity,
+                committed.product.code,
+                committed.requester_cd_id,
+                committed.reservation_id
+            );
+            HttpResponse::Ok().json(committed)
+        }
+        ReservationStatus::Reserved => {
+            if let Err(e) =
+                release_reservation(&mut inventory, reservation, ReservationStatus::Expired)
+            {
+                error!("Failed to persist expiration: {}", e);
+                return ApiError::new(ErrorCode::Internal, "Failed to persist expiration")
+                    .error_response();
+            }
+            ApiError::new(
+                ErrorCode::ReservationExpired,
+                format!("Reservation {} has expired", reservation_id),
+            )
+            .error_response()
+        }
+        ReservationStatus::Expired => ApiError::new(
+            ErrorCode::ReservationExpired,
+            format!("Reservation {} has expired", reservation_id),
+        )
+        .error_response(),
+        ReservationStatus::Aborted => ApiError::new(
+            ErrorCode::ReservationAborted,
+            format!("Reservation {} was aborted", reservation_id),
+        )
+        .error_response(),
+    }
+}
+
+// POST /reservations/{reservation_id}/abort: Cancela a reserva e devolve o estoque.
+pub async fn abort_reservation(
+    path: web::Path<String>,
+    data: web::Data<CdState>,
+    caller: Caller,
+) -> impl Responder {
+    let reservation_id = path.into_inner();
+    let mut inventory = data.inventory.lock().unwrap();
+
+    let Some(reservation) = inventory.reservation(&reservation_id).cloned() else {
+        return reservation_not_found(&reservation_id).error_response();
+    };
+    if let Err(e) = caller.require_cd(&reservation.requester_cd_id) {
+        return e.error_response();
+    }
+
+    match reservation.status {
+        ReservationStatus::Reserved => {
+            match release_reservation(&mut inventory, reservation, ReservationStatus::Aborted) {
+                Ok(aborted) => {
+                    info!(
+                        "Aborted reservation {} of {} for {}",
+                        aborted.reservation_id, aborted.product.code, aborted.requester_cd_id
+                    );
+                    HttpResponse::Ok().json(aborted)
+                }
+                Err(e) => {
+                    error!("Failed to persist abort: {}", e);
+                    ApiError::new(ErrorCode::Internal, "Failed to persist abort").error_response()
+                }
+            }
+        }
+        ReservationStatus::Aborted | ReservationStatus::Expired => {
+            HttpResponse::Ok().json(reservation)
+        }
+        ReservationStatus::Committed => ApiError::new(
+            ErrorCode::Conflict,
+            format!("Reservation {} was already committed", reservation_id),
+        )
+        .error_response(),
+    }
+}
+
+fn reservation_not_found(reservation_id: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        format!("Reservation {} not found", reservation_id),
+    )
+}
+
+fn shutting_down() -> HttpResponse {
+    ApiError::new(ErrorCode::ShuttingDown, "CD is shutting down").error_response()
+}
+
+/// Marks a reserved hold as aborted or expired and puts its stock back.
+fn release_reservation(
+    inventory: &mut InventoryStore,
+    reservation: Reservation,
+    status: ReservationStatus,
+) -> std::io::Result<Reservation> {
+    let restored = match inventory.get(&reservation.product.code) {
+        Some(p) => {
+            let quantity = p
+                .quantity
+                .unwrap_or(0)
+                .checked_add(reservation.quantity)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "releasing reservation {} would overflow the stock of {}",
+                            reservation.reservation_id, reservation.product.code
+                        ),
+                    )
+                })?;
+            Product {
+                quantity: Some(quantity),
+                ..p.clone()
+            }
+        }
+        None => Product {
+            quantity: Some(reservation.quantity),
+            ..reservation.product.clone()
+        },
+    };
+    let change = StockChange {
+        product: restored,
+        reason: MovementReason::ReservationRelease,
+        counterparty_cd_id: Some(reservation.requester_cd_id.clone()),
+        request_id: Some(reservation.reservation_id.clone()),
+    };
+    let released = Reservation {
+        status,
+        ..reservation
+    };
+    inventory.put_reservation(released.clone(), Some(change))?;
+    Ok(released)
+}
+
+pub async fn expire_reservations(state: web::Data<CdState>) {
+    let check_interval = Duration::from_secs(1);
+    let retention = chrono::Duration::days(RESERVATION_RETENTION_DAYS);
+
+    loop {
+        sleep(check_interval).await;
+        let mut inventory = state.inventory.lock().unwrap();
+        let now = Utc::now();
+
+        let expired: Vec<Reservation> = inventory
+            .reservations()
+            .filter(|r| r.status == ReservationStatus::Reserved && r.expires_at <= now)
+            .cloned()
+            .collect();
+        for reservation in expired {
+            let reservation_id = reservation.reservation_id.clone();
+            match release_reservation(&mut inventory, reservation, ReservationStatus::Expired) {
+                Ok(_) => info!("Reservation {} expired", reservation_id),
+                Err(e) => error!("Failed to expire reservation {}: {}", reservation_id, e),
+            }
+        }
+
+        // Reservas resolvidas ficam guardadas para responder a commits repetidos.
+        let stale: Vec<String> = inventory
+            .reservations()
+            .filter(|r| r.status != ReservationStatus::Reserved && now - r.created_at > retention)
+            .map(|r| r.reservation_id.clone())
+            .collect();
+        if let Err(e) = inventory.remove_reservations(stale) {
+            error!("Failed to prune reservations: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_models::{Currency, Money};
+    use std::collections::HashMap;
+
+    struct TempInventory {
+        dir: std::path::PathBuf,
+        store: InventoryStore,
+    }
+
+    impl TempInventory {
+        fn open() -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("cd-handlers-test-{}", uuid::Uuid::new_v4()));
+            let store = InventoryStore::open(&dir, HashMap::new(), Vec::new()).unwrap();
+            TempInventory { dir, store }
+        }
+    }
+
+    impl Drop for TempInventory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn transfer(inventory: &mut InventoryStore, request_id: &str, fingerprint: &str) {
+        let change = StockChange {
+            product: Product {
+                code: "P001".to_string(),
+                name: "Widget".to_string(),
+                price: Money::zero(Currency::BRL),
+                quantity: Some(5),
+            },
+            reason: MovementReason::TransferOut,
+            counterparty_cd_id: Some("cd_beta".to_string()),
+            request_id: Some(request_id.to_string()),
+        };
+        let processed = processed_request(
+            Some(request_id),
+            fingerprint.to_string(),
+            "Transfer successful",
+        );
+        inventory.upsert(change, processed).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn same_request_id_and_payload_replays_the_stored_response() {
+        let mut inventory = TempInventory::open();
+        transfer(&mut inventory.store, "t1", "payload");
+
+        let response = replay_processed(&inventory.store, Some("t1"), "payload").unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("Idempotent-Replayed").unwrap(),
+            "true"
+        );
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(body, "Transfer successful");
+    }
+
+    #[test]
+    fn reused_request_id_with_another_payload_is_a_conflict() {
+        let mut inventory = TempInventory::open();
+        transfer(&mut inventory.store, "t1", "payload");
+
+        let response = replay_processed(&inventory.store, Some("t1"), "other payload").unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(response.headers().get("Idempotent-Replayed").is_none());
+    }
+
+    #[test]
+    fn unknown_or_missing_request_id_is_applied() {
+        let mut inventory = TempInventory::open();
+        transfer(&mut inventory.store, "t1", "payload");
+
+        assert!(replay_processed(&inventory.store, Some("t2"), "payload").is_none());
+        assert!(replay_processed(&inventory.store, None, "payload").is_none());
+        assert!(processed_request(None, "payload".to_string(), "body").is_none());
+    }
 }
@@ -1,205 +1,250 @@
+use actix_web::dev::ServerHandle;
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
-use common_models::Product;
-use common_models::ServiceInfo;
-use reqwest::Client;
+use common_models::{Product, ProductRequestState, ReservationStatus};
+use service_clients::{DiscoveryClient, HubClient};
 use std::collections::HashMap;
 use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::time::{sleep, Duration};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, warn};
 
 mod client;
+mod config;
 mod handlers;
+mod metrics;
+mod replenish;
 mod state;
+mod storage;
+
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: {} <CD_ID> <PORT>", args[0]);
+    let config = match config::CdConfig::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load CD configuration: {}", e);
+            eprintln!("Usage: {} --config <PATH>", args[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let cd_id = config.id.clone();
+    let _telemetry = telemetry::init("cd-service", &cd_id);
+    if let Err(e) = service_auth::init(&cd_id, service_auth::Role::Cd) {
+        error!("{}", e);
         std::process::exit(1);
     }
-
-    let cd_id = args[1].clone();
-    let port: u16 = args[2].parse().expect("Port must be a valid number");
-    let ip = "127.0.0.1".to_string();
-
-    let service_discovery_url = "http://127.0.0.1:8080".to_string();
-    let hub_url = "http://127.0.0.1:8082".to_string();
-
-    let initial_inventory = {
-        let mut map = HashMap::new();
-        match cd_id.as_str() {
-            "cd_alpha" => {
-                map.insert(
-                    "garrafas".to_string(),
-                    Product {
-                        code: "garrafas".to_string(),
-                        name: "Garrafas de Água".to_string(),
-                        price: 2.50,
-                        quantity: Some(50),
-                    },
-                );
-                map.insert(
-                    "celulares".to_string(),
-                    Product {
-                        code: "celulares".to_string(),
-                        name: "Smartphones X".to_string(),
-                        price: 1200.00,
-                        quantity: Some(10),
-                    },
-                );
-            }
-            "cd_beta" => {
-                map.insert(
-                    "garrafas".to_string(),
-                    Product {
-                        code: "garrafas".to_string(),
-                        name: "Garrafas de Água".to_string(),
-                        price: 2.50,
-                        quantity: Some(30),
-                    },
-                );
-                map.insert(
-                    "cadernos".to_string(),
-                    Product {
-                        code: "cadernos".to_string(),
-                        name: "Cadernos Espirais".to_string(),
-                        price: 8.00,
-                        quantity: Some(100),
-                    },
-                );
-            }
-            "cd_gamma" => {
-                map.insert(
-                    "celulares".to_string(),
-                    Product {
-                        code: "celulares".to_string(),
-                        name: "Smartphones X".to_string(),
-                        price: 1200.00,
-                        quantity: Some(15),
-                    },
-                );
-                map.insert(
-                    "canetas".to_string(),
-                    Product {
-                        code: "canetas".to_string(),
-                        name: "Canetas Esferográficas".to_string(),
-                        price: 1.50,
-                        quantity: Some(200),
-                    },
-                );
-            }
-            _ => {
-                map.insert(
-                    "generico".to_string(),
-                    Product {
-                        code: "generico".to_string(),
-                        name: "Produto Genérico".to_string(),
-                        price: 5.00,
-                        quantity: Some(5),
-                    },
-                );
-            }
+    let tls = match config.tls.as_ref().map(|files| files.load()).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("Invalid TLS setup: {}", e);
+            std::process::exit(1);
         }
-        map
     };
+    let ip = config.advertised_ip.clone();
+    let port = config.advertised_port;
+    let scheme = service_auth::scheme(tls.as_ref());
 
-    let cd_state = web::Data::new(state::CdState {
-        inventory: Arc::new(Mutex::new(initial_inventory)),
-        service_discovery_url: service_discovery_url.clone(),
-        hub_url: hub_url.clone(),
-        http_client: Client::new(),
-        own_id: cd_id.clone(),
-    });
-
-    let client_for_register = Client::new();
-    let service_info = ServiceInfo {
-        id: cd_id.clone(),
-        ip: ip.clone(),
-        port,
-        last_heartbeat: chrono::Utc::now(),
-    };
-    let discovery_url_register = format!("{}/register", service_discovery_url);
-
-    match client_for_register
-        .post(&discovery_url_register)
-        .json(&service_info)
-        .send()
-        .await
-    {
-        Ok(resp) if resp.status().is_success() => {
-            println!(
-                "[{}] Registered with Service Discovery at {}:{}",
-                cd_id, ip, port
-            );
-        }
-        Ok(resp) => {
-            eprintln!(
-                "[{}] Failed to register with Service Discovery: Status {} - {:?}",
-                cd_id,
-                resp.status(),
-                resp.text().await
-            );
+    let http_client = service_clients::http_client(service_clients::DEFAULT_TIMEOUT, tls.as_ref());
+    let discovery = DiscoveryClient::new(http_client.clone(), &config.discovery_urls);
+    let hub = HubClient::new(http_client.clone(), &config.hub_url);
+    let (discovery, hub) = match (discovery, hub) {
+        (Ok(discovery), Ok(hub)) => (discovery, hub),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Invalid service URL: {}", e);
             std::process::exit(1);
         }
+    };
+
+    let initial_inventory: HashMap<String, Product> = config
+        .inventory
+        .iter()
+        .map(|product| (product.code.clone(), product.clone()))
+        .collect();
+
+    let inventory = match storage::InventoryStore::open(
+        &config.data_dir,
+        initial_inventory,
+        config.reorder_policies.clone(),
+    ) {
+        Ok(inventory) => inventory,
         Err(e) => {
-            eprintln!(
-                "[{}] Failed to register with Service Discovery: {}",
-                cd_id, e
+            error!(
+                "Failed to open inventory storage at {}: {}",
+                config.data_dir.display(),
+                e
             );
             std::process::exit(1);
         }
-    }
+    };
+
+    let cd_state = web::Data::new(state::CdState {
+        inventory: Arc::new(Mutex::new(inventory)),
+        product_requests: Arc::new(Mutex::new(HashMap::new())),
+        discovery,
+        hub,
+        http_client,
+        own_id: cd_id.clone(),
+        advertised_ip: ip.clone(),
+        advertised_port: port,
+        advertised_scheme: scheme,
+        tags: config.tags.clone(),
+        metadata: config.metadata.clone(),
+        heartbeat_interval: config.heartbeat_interval,
+        shutting_down: AtomicBool::new(false),
+        replenish_interval: config.replenish_interval,
+        replenish_cooldown: config.replenish_cooldown,
+        replenishments: Mutex::new(HashMap::new()),
+        metrics: metrics::Metrics::new(),
+    });
 
     let heartbeat_state = cd_state.clone();
     tokio::spawn(client::send_heartbeat(heartbeat_state));
 
-    let request_state = cd_state.clone();
-    tokio::spawn(async move {
-        if request_state.own_id == "cd_alpha" {
-            sleep(Duration::from_secs(5)).await;
-            if let Err(e) = client::request_product_from_system(
-                request_state.clone(),
-                "celulares".to_string(),
-                12,
-            )
-            .await
-            {
-                eprintln!(
-                    "[{}] Error fulfilling request for celulares: {}",
-                    request_state.own_id, e
-                );
-            }
-        }
-        if request_state.own_id == "cd_beta" {
-            sleep(Duration::from_secs(7)).await;
-            if let Err(e) = client::request_product_from_system(
-                request_state.clone(),
-                "canetas".to_string(),
-                50,
-            )
-            .await
-            {
-                eprintln!(
-                    "[{}] Error fulfilling request for canetas: {}",
-                    request_state.own_id, e
-                );
-            }
-        }
-    });
+    tokio::spawn(client::publish_stock_events(cd_state.clone()));
 
-    println!("[{}] CD Service running on http://{}:{}", cd_id, ip, port);
+    let expiration_state = cd_state.clone();
+    tokio::spawn(handlers::expire_reservations(expiration_state));
 
-    HttpServer::new(move || {
+    let idempotency_state = cd_state.clone();
+    tokio::spawn(handlers::expire_processed_requests(idempotency_state));
+
+    tokio::spawn(handlers::expire_product_requests(cd_state.clone()));
+
+    let recovery_state = cd_state.clone();
+    tokio::spawn(client::resolve_pending_inbound(recovery_state));
+
+    tokio::spawn(replenish::replenish_stock(cd_state.clone()));
+
+    info!(
+        "CD Service running on {}://{} (advertised as {}:{})",
+        scheme, config.bind_address, ip, port
+    );
+
+    let server_state = cd_state.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(cd_state.clone())
+            .app_data(server_state.clone())
+            .wrap(from_fn(service_auth::authenticate))
+            .wrap(from_fn(telemetry::trace_id_header))
+            .wrap(telemetry::TracingLogger::default())
+            .configure(common_models::configure_error_responses)
+            .service(web::resource("/inventory").get(handlers::list_inventory))
             .service(
                 web::resource("/inventory/{product_code}").get(handlers::get_product_inventory),
             )
+            .service(web::resource("/ledger").get(handlers::get_ledger))
+            .service(web::resource("/stock_snapshot").get(handlers::get_stock_snapshot))
+            .service(web::resource("/reports/valuation").get(handlers::get_valuation_report))
+            .service(web::resource("/metrics").get(handlers::get_metrics))
             .service(web::resource("/transfer_product").post(handlers::transfer_product))
             .service(web::resource("/receive_product").post(handlers::receive_product))
-    })
-    .bind(format!("{}:{}", ip, port))?
-    .run()
-    .await
+            .service(
+                web::resource("/requests")
+                    .get(handlers::list_product_requests)
+                    .post(handlers::create_product_request),
+            )
+            .service(web::resource("/requests/{request_id}").get(handlers::get_product_request))
+            .service(web::resource("/reorder_policies").get(handlers::list_reorder_policies))
+            .service(
+                web::resource("/reorder_policies/{product_code}")
+                    .get(handlers::get_reorder_policy)
+                    .put(handlers::put_reorder_policy)
+                    .delete(handlers::delete_reorder_policy),
+            )
+            .service(web::resource("/reservations").post(handlers::reserve_product))
+            .service(web::resource("/reservations/{reservation_id}").get(handlers::get_reservation))
+            .service(
+                web::resource("/reservations/{reservation_id}/commit")
+                    .post(handlers::commit_reservation),
+            )
+            .service(
+                web::resource("/reservations/{reservation_id}/abort")
+                    .post(handlers::abort_reservation),
+            )
+    });
+    let server = match &tls {
+        Some(tls) => server.bind_rustls_0_23(config.bind_address, tls.server_config())?,
+        None => server.bind(config.bind_address)?,
+    }
+    .disable_signals()
+    .run();
+
+    tokio::spawn(shutdown_on_signal(cd_state, server.handle()));
+    server.await
+}
+
+/// Waits for Ctrl-C or SIGTERM, leaves Service Discovery so no one sends new
+/// work here, lets in-flight transfers finish and then stops the HTTP server.
+async fn shutdown_on_signal(state: web::Data<state::CdState>, server: ServerHandle) {
+    wait_for_signal().await;
+    info!("Shutdown requested");
+    state.shutting_down.store(true, Ordering::SeqCst);
+
+    client::deregister_from_discovery(&state).await;
+
+    let deadline = Instant::now() + SHUTDOWN_DRAIN_TIMEOUT;
+    loop {
+        let running_requests = state
+            .product_requests
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|r| {
+                matches!(
+                    r.state,
+                    ProductRequestState::Pending | ProductRequestState::Running
+                )
+            })
+            .count();
+        let (open_reservations, pending_inbound) = {
+            let inventory = state.inventory.lock().unwrap();
+            let open_reservations = inventory
+                .reservations()
+                .filter(|r| r.status == ReservationStatus::Reserved)
+                .count();
+            // Transferências de entrada com o commit ainda sem resposta: o
+            // `resolve_pending_inbound` continua tentando durante a espera.
+            (open_reservations, inventory.pending_inbound().count())
+        };
+
+        if running_requests == 0 && open_reservations == 0 && pending_inbound == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "Drain timed out with {} request(s), {} reservation(s) and {} inbound transfer(s) still open",
+                running_requests, open_reservations, pending_inbound
+            );
+            break;
+        }
+        info!(
+            "Waiting for {} request(s), {} reservation(s) and {} inbound transfer(s) to finish",
+            running_requests, open_reservations, pending_inbound
+        );
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    info!("Stopping HTTP server");
+    server.stop(true).await;
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,75 @@
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+
+/// Prometheus metrics of the CD, served on `/metrics`.
+pub struct Metrics {
+    pub registry: Registry,
+    /// Set from the inventory on every scrape.
+    pub stock_quantity: IntGaugeVec,
+    /// Completed transfers by `direction`, `in` or `out`.
+    pub transfers: IntCounterVec,
+    pub transferred_units: IntCounterVec,
+    /// Finished product requests by `outcome`, `completed` or `failed`.
+    pub product_requests: IntCounterVec,
+}
+
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let stock_quantity = IntGaugeVec::new(
+            Opts::new("cd_stock_quantity", "Quantity in stock per product"),
+            &["product_code"],
+        )
+        .unwrap();
+        let transfers = IntCounterVec::new(
+            Opts::new("cd_transfers_total", "Transfers to or from other CDs"),
+            &["direction"],
+        )
+        .unwrap();
+        let transferred_units = IntCounterVec::new(
+            Opts::new(
+                "cd_transferred_units_total",
+                "Units transferred to or from other CDs",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let product_requests = IntCounterVec::new(
+            Opts::new("cd_product_requests_total", "Finished product requests"),
+            &["outcome"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(stock_quantity.clone())).unwrap();
+        registry.register(Box::new(transfers.clone())).unwrap();
+        registry
+            .register(Box::new(transferred_units.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(product_requests.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            stock_quantity,
+            transfers,
+            transferred_units,
+            product_requests,
+        }
+    }
+
+    pub fn record_transfer(&self, direction: Direction, quantity: u32) {
+        let direction = match direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        };
+        self.transfers.with_label_values(&[direction]).inc();
+        self.transferred_units
+            .with_label_values(&[direction])
+            .inc_by(u64::from(quantity));
+    }
+}
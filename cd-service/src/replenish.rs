@@ -0,0 +1,285 @@
+use super::client;
+use super::state::CdState;
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use common_models::{ProductRequest, ProductRequestState, ReorderPolicy, ReorderPolicyStatus};
+use std::sync::atomic::Ordering;
+use tokio::time::{sleep, Duration};
+use tracing::info;
+
+/// Cap on the cooldown after repeated failures, however many there were.
+const MAX_FAILURE_COOLDOWN: Duration = Duration::from_secs(15 * 60);
+
+/// Automatic replenishment of one product, kept in memory only: after a
+/// restart every product starts without a cooldown.
+#[derive(Debug, Default, Clone)]
+pub struct Replenishment {
+    pub request_id: Option<String>,
+    pub last_triggered_at: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+}
+
+impl Replenishment {
+    /// Earliest time a new replenishment may start. The cooldown doubles
+    /// with each failed replenishment in a row, so a product no CD has does
+    /// not turn into a request every few seconds.
+    fn next_attempt_after(&self, cooldown: Duration) -> Option<DateTime<Utc>> {
+        let last = self.last_triggered_at?;
+        let wait = cooldown
+            .saturating_mul(1 << self.consecutive_failures.min(16))
+            .min(MAX_FAILURE_COOLDOWN.max(cooldown));
+        let wait = chrono::Duration::from_std(wait).unwrap_or(chrono::Duration::MAX);
+        Some(
+            last.checked_add_signed(wait)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        )
+    }
+}
+
+fn cooldown_of(state: &CdState, policy: &ReorderPolicy) -> Duration {
+    policy
+        .cooldown_secs
+        .map_or(state.replenish_cooldown, Duration::from_secs)
+}
+
+/// Forgets the request of a replenishment once it has finished, counting
+/// whether it failed. Requests still pending or running are left alone.
+fn settle(state: &CdState, replenishment: &mut Replenishment) {
+    let Some(request_id) = &replenishment.request_id else {
+        return;
+    };
+    let finished = state
+        .product_requests
+        .lock()
+        .unwrap()
+        .get(request_id)
+        .map(|r| r.state);
+    settle_as(replenishment, finished);
+}
+
+/// `finished` is the state of the replenishment's request, `None` when the
+/// request is no longer known.
+fn settle_as(replenishment: &mut Replenishment, finished: Option<ProductRequestState>) {
+    match finished {
+        Some(ProductRequestState::Pending | ProductRequestState::Running) => return,
+        Some(ProductRequestState::Completed) => replenishment.consecutive_failures = 0,
+        Some(ProductRequestState::Failed) | None => replenishment.consecutive_failures += 1,
+    }
+    replenishment.request_id = None;
+}
+
+pub fn policy_status(state: &CdState, policy: ReorderPolicy) -> ReorderPolicyStatus {
+    let quantity = state
+        .inventory
+        .lock()
+        .unwrap()
+        .get(&policy.product_code)
+        .and_then(|p| p.quantity)
+        .unwrap_or(0);
+    let cooldown = cooldown_of(state, &policy);
+    let mut replenishments = state.replenishments.lock().unwrap();
+    let replenishment = replenishments
+        .entry(policy.product_code.clone())
+        .or_default();
+    settle(state, replenishment);
+    status_of(policy, quantity, replenishment, cooldown)
+}
+
+fn status_of(
+    policy: ReorderPolicy,
+    quantity: u32,
+    replenishment: &Replenishment,
+    cooldown: Duration,
+) -> ReorderPolicyStatus {
+    ReorderPolicyStatus {
+        quantity,
+        below_minimum: quantity < policy.min_quantity,
+        active_request_id: replenishment.request_id.clone(),
+        last_triggered_at: replenishment.last_triggered_at,
+        consecutive_failures: replenishment.consecutive_failures,
+        next_attempt_after: replenishment.next_attempt_after(cooldown),
+        policy,
+    }
+}
+
+/// The product request that brings a product back to its target. A product
+/// request already tops the stock up to `quantity`, fetching only what is
+/// missing, so it asks for the target itself and not for the difference.
+fn replenishment_request(policy: &ReorderPolicy) -> ProductRequest {
+    ProductRequest {
+        product_code: policy.product_code.clone(),
+        quantity: policy.target_quantity,
+    }
+}
+
+/// Checks the stock of every product with a reorder policy and, when it is
+/// below the minimum, starts a product request for the target level. Only one
+/// replenishment per product runs at a time, and a new one waits for the
+/// product's cooldown.
+pub async fn replenish_stock(state: web::Data<CdState>) {
+    loop {
+        sleep(state.replenish_interval).await;
+        if state.shutting_down.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let policies: Vec<ReorderPolicy> = state
+            .inventory
+            .lock()
+            .unwrap()
+            .reorder_policies()
+            .cloned()
+            .collect();
+
+        for policy in policies {
+            let status = policy_status(&state, policy);
+            if !status.below_minimum || status.active_request_id.is_some() {
+                continue;
+            }
+            if status
+                .next_attempt_after
+                .is_some_and(|next| Utc::now() < next)
+            {
+                continue;
+            }
+
+            let policy = status.policy;
+            info!(
+                "Stock of {} is {}, below the minimum of {}; replenishing to {}",
+                policy.product_code, status.quantity, policy.min_quantity, policy.target_quantity
+            );
+            let request =
+                client::start_product_request(state.clone(), replenishment_request(&policy));
+            let mut replenishments = state.replenishments.lock().unwrap();
+            let replenishment = replenishments.entry(policy.product_code).or_default();
+            replenishment.request_id = Some(request.request_id);
+            replenishment.last_triggered_at = Some(request.created_at);
+        }
+
+        // Produtos sem política não precisam mais do estado de reposição.
+        {
+            let inventory = state.inventory.lock().unwrap();
+            state
+                .replenishments
+                .lock()
+                .unwrap()
+                .retain(|code, _| inventory.reorder_policy(code).is_some());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(min_quantity: u32, target_quantity: u32) -> ReorderPolicy {
+        ReorderPolicy {
+            product_code: "P1".to_string(),
+            min_quantity,
+            target_quantity,
+            cooldown_secs: None,
+        }
+    }
+
+    fn failed(times: u32, at: DateTime<Utc>) -> Replenishment {
+        Replenishment {
+            request_id: None,
+            last_triggered_at: Some(at),
+            consecutive_failures: times,
+        }
+    }
+
+    #[test]
+    fn never_triggered_has_no_cooldown() {
+        let replenishment = Replenishment::default();
+        assert_eq!(
+            replenishment.next_attempt_after(Duration::from_secs(60)),
+            None
+        );
+    }
+
+    #[test]
+    fn cooldown_doubles_with_each_failure_up_to_the_cap() {
+        let at = Utc::now();
+        let cooldown = Duration::from_secs(60);
+        let wait = |failures| failed(failures, at).next_attempt_after(cooldown).unwrap() - at;
+
+        assert_eq!(wait(0), chrono::Duration::seconds(60));
+        assert_eq!(wait(1), chrono::Duration::seconds(120));
+        assert_eq!(wait(3), chrono::Duration::seconds(480));
+        assert_eq!(wait(4), chrono::Duration::minutes(15));
+        assert_eq!(wait(u32::MAX), chrono::Duration::minutes(15));
+    }
+
+    #[test]
+    fn cooldown_longer_than_the_cap_is_kept() {
+        let at = Utc::now();
+        let next = failed(5, at)
+            .next_attempt_after(Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(next - at, chrono::Duration::hours(1));
+    }
+
+    #[test]
+    fn settling_counts_failures_and_resets_on_success() {
+        let mut replenishment = Replenishment {
+            request_id: Some("r1".to_string()),
+            ..Default::default()
+        };
+        settle_as(&mut replenishment, Some(ProductRequestState::Running));
+        assert_eq!(replenishment.request_id.as_deref(), Some("r1"));
+        assert_eq!(replenishment.consecutive_failures, 0);
+
+        settle_as(&mut replenishment, Some(ProductRequestState::Failed));
+        assert_eq!(replenishment.request_id, None);
+        assert_eq!(replenishment.consecutive_failures, 1);
+
+        // Um pedido esquecido conta como falha.
+        replenishment.request_id = Some("r2".to_string());
+        settle_as(&mut replenishment, None);
+        assert_eq!(replenishment.consecutive_failures, 2);
+
+        replenishment.request_id = Some("r3".to_string());
+        settle_as(&mut replenishment, Some(ProductRequestState::Completed));
+        assert_eq!(replenishment.request_id, None);
+        assert_eq!(replenishment.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn status_is_below_minimum_only_under_min_quantity() {
+        let replenishment = Replenishment::default();
+        let cooldown = Duration::from_secs(60);
+
+        assert!(status_of(policy(5, 20), 4, &replenishment, cooldown).below_minimum);
+        assert!(!status_of(policy(5, 20), 5, &replenishment, cooldown).below_minimum);
+        assert!(!status_of(policy(0, 20), 0, &replenishment, cooldown).below_minimum);
+    }
+
+    #[test]
+    fn status_reports_the_replenishment() {
+        let at = Utc::now();
+        let replenishment = Replenishment {
+            request_id: Some("r1".to_string()),
+            ..failed(2, at)
+        };
+        let status = status_of(policy(5, 20), 3, &replenishment, Duration::from_secs(10));
+
+        assert_eq!(status.quantity, 3);
+        assert_eq!(status.active_request_id.as_deref(), Some("r1"));
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.last_triggered_at, Some(at));
+        assert_eq!(
+            status.next_attempt_after,
+            Some(at + chrono::Duration::seconds(40))
+        );
+    }
+
+    #[test]
+    fn replenishment_asks_for_the_target_level() {
+        // O pedido completa o estoque até `quantity`, então com 3 unidades e
+        // alvo 20 só 17 vêm de outros CDs e o estoque termina em 20.
+        let request = replenishment_request(&policy(5, 20));
+        assert_eq!(request.product_code, "P1");
+        assert_eq!(request.quantity, 20);
+    }
+}
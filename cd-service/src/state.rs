@@ -1,12 +1,34 @@
-use common_models::Product;
+use super::metrics::Metrics;
+use super::replenish::Replenishment;
+use super::storage::InventoryStore;
+use common_models::ProductRequestStatus;
 use reqwest::Client;
-use std::collections::HashMap;
+use service_clients::{DiscoveryClient, HubClient};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub struct CdState {
-    pub inventory: Arc<Mutex<HashMap<String, Product>>>,
-    pub service_discovery_url: String,
-    pub hub_url: String,
+    pub inventory: Arc<Mutex<InventoryStore>>,
+    pub product_requests: Arc<Mutex<HashMap<String, ProductRequestStatus>>>,
+    pub discovery: DiscoveryClient,
+    pub hub: HubClient,
+    /// Shared by the `CdClient`s built for other CDs.
     pub http_client: Client,
     pub own_id: String,
+    pub advertised_ip: String,
+    pub advertised_port: u16,
+    /// `https` when the CD listens with TLS.
+    pub advertised_scheme: &'static str,
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub heartbeat_interval: Duration,
+    pub shutting_down: AtomicBool,
+    pub replenish_interval: Duration,
+    /// Cooldown of reorder policies that do not set their own.
+    pub replenish_cooldown: Duration,
+    /// Automatic replenishment state per product with a reorder policy.
+    pub replenishments: Mutex<HashMap<String, Replenishment>>,
+    pub metrics: Metrics,
 }
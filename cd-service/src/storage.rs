@@ -0,0 +1,827 @@
+use chrono::{DateTime, Utc};
+use common_models::{MovementReason, Product, ReorderPolicy, Reservation, StockMovement};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const SNAPSHOT_FILE: &str = "inventory.snapshot.json";
+const WAL_FILE: &str = "inventory.wal";
+const COMPACT_AFTER_RECORDS: usize = 1000;
+
+/// Stock this CD has asked another CD to commit to it but has not credited yet.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PendingInbound {
+    pub reservation_id: String,
+    pub source_cd_id: String,
+    pub product: Product,
+    pub quantity: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// New state of a product plus why it changed, recorded in the movement ledger.
+#[derive(Debug, Clone)]
+pub struct StockChange {
+    pub product: Product,
+    pub reason: MovementReason,
+    pub counterparty_cd_id: Option<String>,
+    pub request_id: Option<String>,
+}
+
+/// Outcome of an idempotent request, kept so a retry gets the same answer.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcessedRequest {
+    pub request_id: String,
+    pub fingerprint: String,
+    pub status: u16,
+    pub body: String,
+    pub processed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WalRecord {
+    Upsert {
+        product: Product,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        processed: Option<ProcessedRequest>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        movement: Option<StockMovement>,
+    },
+    RemoveProcessed {
+        request_ids: Vec<String>,
+    },
+    PutReservation {
+        reservation: Reservation,
+        product: Option<Product>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        movement: Option<StockMovement>,
+    },
+    RemoveReservations {
+        reservation_ids: Vec<String>,
+    },
+    CommitReservation {
+        reservation: Reservation,
+        movements: Vec<StockMovement>,
+    },
+    PutInbound {
+        inbound: PendingInbound,
+    },
+    FinishInbound {
+        reservation_id: String,
+        product: Option<Product>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        movement: Option<StockMovement>,
+    },
+    PutReorderPolicy {
+        policy: ReorderPolicy,
+    },
+    RemoveReorderPolicy {
+        product_code: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct WalEntry {
+    seq: u64,
+    #[serde(flatten)]
+    record: WalRecord,
+}
+
+#[derive(Serialize)]
+struct WalEntryRef<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    record: &'a WalRecord,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Snapshot {
+    last_seq: u64,
+    products: HashMap<String, Product>,
+    #[serde(default)]
+    reservations: HashMap<String, Reservation>,
+    #[serde(default)]
+    pending_inbound: HashMap<String, PendingInbound>,
+    #[serde(default)]
+    processed_requests: HashMap<String, ProcessedRequest>,
+    #[serde(default)]
+    ledger: Vec<StockMovement>,
+    #[serde(default)]
+    reorder_policies: HashMap<String, ReorderPolicy>,
+}
+
+impl Snapshot {
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Upsert {
+                product,
+                processed,
+                movement,
+            } => {
+                self.products.insert(product.code.clone(), product);
+                self.ledger.extend(movement);
+                if let Some(processed) = processed {
+                    self.processed_requests
+                        .insert(processed.request_id.clone(), processed);
+                }
+            }
+            WalRecord::RemoveProcessed { request_ids } => {
+                for id in request_ids {
+                    self.processed_requests.remove(&id);
+                }
+            }
+            WalRecord::PutReservation {
+                reservation,
+                product,
+                movement,
+            } => {
+                if let Some(product) = product {
+                    self.products.insert(product.code.clone(), product);
+                }
+                self.ledger.extend(movement);
+                self.reservations
+                    .insert(reservation.reservation_id.clone(), reservation);
+            }
+            WalRecord::CommitReservation {
+                reservation,
+                movements,
+            } => {
+                self.ledger.extend(movements);
+                self.reservations
+                    .insert(reservation.reservation_id.clone(), reservation);
+            }
+            WalRecord::RemoveReservations { reservation_ids } => {
+                for id in reservation_ids {
+                    self.reservations.remove(&id);
+                }
+            }
+            WalRecord::PutInbound { inbound } => {
+                self.pending_inbound
+                    .insert(inbound.reservation_id.clone(), inbound);
+            }
+            WalRecord::FinishInbound {
+                reservation_id,
+                product,
+                movement,
+            } => {
+                if let Some(product) = product {
+                    self.products.insert(product.code.clone(), product);
+                }
+                self.ledger.extend(movement);
+                self.pending_inbound.remove(&reservation_id);
+            }
+            WalRecord::PutReorderPolicy { policy } => {
+                self.reorder_policies
+                    .insert(policy.product_code.clone(), policy);
+            }
+            WalRecord::RemoveReorderPolicy { product_code } => {
+                self.reorder_policies.remove(&product_code);
+            }
+        }
+    }
+}
+
+/// Inventory backed by a snapshot file plus an append-only write-ahead log.
+///
+/// Every change is appended to the log and synced to disk before it is applied
+/// in memory, so a change is committed once `upsert` returns `Ok`. On startup
+/// the snapshot is loaded and the log is replayed on top of it.
+pub struct InventoryStore {
+    state: Snapshot,
+    snapshot_path: PathBuf,
+    wal_path: PathBuf,
+    wal: File,
+    /// Set when a failed write could not be undone, leaving a partial record
+    /// at the end of the log.
+    wal_broken: bool,
+    records_since_snapshot: usize,
+}
+
+impl InventoryStore {
+    /// Opens the store in `data_dir`, recovering the last committed state.
+    /// `seed` and `seed_policies` are only used when the directory holds no
+    /// previous state.
+    pub fn open(
+        data_dir: &Path,
+        seed: HashMap<String, Product>,
+        seed_policies: Vec<ReorderPolicy>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let snapshot_path = data_dir.join(SNAPSHOT_FILE);
+        let wal_path = data_dir.join(WAL_FILE);
+
+        let state = if snapshot_path.exists() || wal_path.exists() {
+            recover(&snapshot_path, &wal_path)?
+        } else {
+            let now = Utc::now();
+            let mut seed: Vec<Product> = seed.into_values().collect();
+            seed.sort_by(|a, b| a.code.cmp(&b.code));
+            let ledger = seed
+                .iter()
+                .enumerate()
+                .map(|(i, product)| StockMovement {
+                    id: i as u64 + 1,
+                    timestamp: now,
+                    product_code: product.code.clone(),
+                    delta: product.quantity.unwrap_or(0) as i64,
+                    quantity_after: product.quantity.unwrap_or(0),
+                    reason: MovementReason::Adjustment,
+                    counterparty_cd_id: None,
+                    request_id: None,
+                })
+                .collect();
+            Snapshot {
+                products: seed.into_iter().map(|p| (p.code.clone(), p)).collect(),
+                ledger,
+                reorder_policies: seed_policies
+                    .into_iter()
+                    .map(|p| (p.product_code.clone(), p))
+                    .collect(),
+                ..Snapshot::default()
+            }
+        };
+
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&wal_path)?;
+
+        let mut store = InventoryStore {
+            state,
+            snapshot_path,
+            wal_path,
+            wal,
+            wal_broken: false,
+            records_since_snapshot: 0,
+        };
+        store.compact()?;
+        Ok(store)
+    }
+
+    pub fn get(&self, product_code: &str) -> Option<&Product> {
+        self.state.products.get(product_code)
+    }
+
+    pub fn products(&self) -> impl Iterator<Item = &Product> {
+        self.state.products.values()
+    }
+
+    pub fn reservation(&self, reservation_id: &str) -> Option<&Reservation> {
+        self.state.reservations.get(reservation_id)
+    }
+
+    pub fn reservations(&self) -> impl Iterator<Item = &Reservation> {
+        self.state.reservations.values()
+    }
+
+    pub fn processed_request(&self, request_id: &str) -> Option<&ProcessedRequest> {
+        self.state.processed_requests.get(request_id)
+    }
+
+    pub fn processed_requests(&self) -> impl Iterator<Item = &ProcessedRequest> {
+        self.state.processed_requests.values()
+    }
+
+    pub fn pending_inbound(&self) -> impl Iterator<Item = &PendingInbound> {
+        self.state.pending_inbound.values()
+    }
+
+    pub fn is_pending_inbound(&self, reservation_id: &str) -> bool {
+        self.state.pending_inbound.contains_key(reservation_id)
+    }
+
+    /// Every stock movement since the store was created, oldest first.
+    pub fn ledger(&self) -> &[StockMovement] {
+        &self.state.ledger
+    }
+
+    /// Movements with an id greater than `id`. Ids start at 1 and have no
+    /// gaps, so they double as sequence numbers for the hub's stock events.
+    pub fn movements_after(&self, id: u64) -> &[StockMovement] {
+        let start = (id as usize).min(self.state.ledger.len());
+        &self.state.ledger[start..]
+    }
+
+    pub fn last_movement_id(&self) -> u64 {
+        self.state.ledger.last().map_or(0, |m| m.id)
+    }
+
+    pub fn reorder_policy(&self, product_code: &str) -> Option<&ReorderPolicy> {
+        self.state.reorder_policies.get(product_code)
+    }
+
+    pub fn reorder_policies(&self) -> impl Iterator<Item = &ReorderPolicy> {
+        self.state.reorder_policies.values()
+    }
+
+    /// Durably records the new state of a product and the ledger movement that
+    /// led to it, remembering the outcome of the request that caused it, if
+    /// any, in the same log record.
+    pub fn upsert(
+        &mut self,
+        change: StockChange,
+        processed: Option<ProcessedRequest>,
+    ) -> io::Result<()> {
+        let (product, movement) = self.movement_for(change);
+        self.commit(WalRecord::Upsert {
+            product,
+            processed,
+            movement: Some(movement),
+        })
+    }
+
+    pub fn remove_processed(&mut self, request_ids: Vec<String>) -> io::Result<()> {
+        if request_ids.is_empty() {
+            return Ok(());
+        }
+        self.commit(WalRecord::RemoveProcessed { request_ids })
+    }
+
+    /// Records a reservation together with the product stock it affects, so
+    /// both change in a single log record.
+    pub fn put_reservation(
+        &mut self,
+        reservation: Reservation,
+        change: Option<StockChange>,
+    ) -> io::Result<()> {
+        let (product, movement) = self.split_change(change);
+        self.commit(WalRecord::PutReservation {
+            reservation,
+            product,
+            movement,
+        })
+    }
+
+    /// Marks `reservation` committed. Its units already left the stock with
+    /// the hold, so the ledger gets the hold released and the same units
+    /// going out as a transfer: the stock does not change, but the transfer
+    /// shows up as `TransferOut` with its real quantity.
+    pub fn commit_reservation(&mut self, reservation: Reservation) -> io::Result<()> {
+        let mut movements = Vec::new();
+        if let Some(held) = self.get(&reservation.product.code).and_then(|p| p.quantity) {
+            let released = held.checked_add(reservation.quantity).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "releasing reservation {} would overflow the stock of {}",
+                        reservation.reservation_id, reservation.product.code
+                    ),
+                )
+            })?;
+            let next_id = self.last_movement_id() + 1;
+            let movement = |id, delta, quantity_after, reason| StockMovement {
+                id,
+                timestamp: Utc::now(),
+                product_code: reservation.product.code.clone(),
+                delta,
+                quantity_after,
+                reason,
+                counterparty_cd_id: Some(reservation.requester_cd_id.clone()),
+                request_id: Some(reservation.reservation_id.clone()),
+            };
+            let quantity = i64::from(reservation.quantity);
+            movements.push(movement(
+                next_id,
+                quantity,
+                released,
+                MovementReason::ReservationRelease,
+            ));
+            movements.push(movement(
+                next_id + 1,
+                -quantity,
+                held,
+                MovementReason::TransferOut,
+            ));
+        }
+        self.commit(WalRecord::CommitReservation {
+            reservation,
+            movements,
+        })
+    }
+
+    pub fn remove_reservations(&mut self, reservation_ids: Vec<String>) -> io::Result<()> {
+        if reservation_ids.is_empty() {
+            return Ok(());
+        }
+        self.commit(WalRecord::RemoveReservations { reservation_ids })
+    }
+
+    pub fn put_inbound(&mut self, inbound: PendingInbound) -> io::Result<()> {
+        self.commit(WalRecord::PutInbound { inbound })
+    }
+
+    /// Clears a pending inbound transfer, crediting the stock in the same
+    /// record when the source committed it. Fails when the transfer is not
+    /// pending, so a transfer is never settled twice.
+    pub fn finish_inbound(
+        &mut self,
+        reservation_id: &str,
+        change: Option<StockChange>,
+    ) -> io::Result<()> {
+        if !self.is_pending_inbound(reservation_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No pending inbound transfer {}", reservation_id),
+            ));
+        }
+        let (product, movement) = self.split_change(change);
+        self.commit(WalRecord::FinishInbound {
+            reservation_id: reservation_id.to_string(),
+            product,
+            movement,
+        })
+    }
+
+    pub fn put_reorder_policy(&mut self, policy: ReorderPolicy) -> io::Result<()> {
+        self.commit(WalRecord::PutReorderPolicy { policy })
+    }
+
+    pub fn remove_reorder_policy(&mut self, product_code: &str) -> io::Result<()> {
+        self.commit(WalRecord::RemoveReorderPolicy {
+            product_code: product_code.to_string(),
+        })
+    }
+
+    fn split_change(
+        &self,
+        change: Option<StockChange>,
+    ) -> (Option<Product>, Option<StockMovement>) {
+        match change {
+            Some(change) => {
+                let (product, movement) = self.movement_for(change);
+                (Some(product), Some(movement))
+            }
+            None => (None, None),
+        }
+    }
+
+    fn movement_for(&self, change: StockChange) -> (Product, StockMovement) {
+        let before = self
+            .get(&change.product.code)
+            .and_then(|p| p.quantity)
+            .unwrap_or(0);
+        let after = change.product.quantity.unwrap_or(0);
+        let movement = StockMovement {
+            id: self.state.ledger.last().map_or(1, |m| m.id + 1),
+            timestamp: Utc::now(),
+            product_code: change.product.code.clone(),
+            delta: after as i64 - before as i64,
+            quantity_after: after,
+            reason: change.reason,
+            counterparty_cd_id: change.counterparty_cd_id,
+            request_id: change.request_id,
+        };
+        (change.product, movement)
+    }
+
+    fn commit(&mut self, record: WalRecord) -> io::Result<()> {
+        let seq = self.append(&record)?;
+        self.state.apply(record);
+        self.state.last_seq = seq;
+
+        // The change is durable once appended. A failed compaction only
+        // leaves a longer log, so it must not make the caller think the
+        // change failed and retry it.
+        if self.records_since_snapshot >= COMPACT_AFTER_RECORDS {
+            if let Err(e) = self.compact() {
+                warn!("Failed to compact {:?}: {}", self.wal_path, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, record: &WalRecord) -> io::Result<u64> {
+        let seq = self.state.last_seq + 1;
+        let mut line = serde_json::to_vec(&WalEntryRef { seq, record })?;
+        line.push(b'\n');
+        if self.wal_broken {
+            return Err(io::Error::other(
+                "Inventory log is unusable after a failed write; restart the CD",
+            ));
+        }
+        let len = self.wal.metadata()?.len();
+        if let Err(e) = self
+            .wal
+            .write_all(&line)
+            .and_then(|()| self.wal.sync_data())
+        {
+            // Drop whatever part of the record made it to the log, or the next
+            // record would follow it and recovery would reject the log as
+            // corrupted. If even that fails, stop writing to the log at all.
+            if self
+                .wal
+                .set_len(len)
+                .and_then(|()| self.wal.sync_data())
+                .is_err()
+            {
+                self.wal_broken = true;
+            }
+            return Err(e);
+        }
+        self.records_since_snapshot += 1;
+        Ok(seq)
+    }
+
+    /// Writes the in-memory state to a new snapshot and truncates the log.
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.snapshot_path.with_extension("json.tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&serde_json::to_vec_pretty(&self.state)?)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.snapshot_path)?;
+
+        self.wal = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.wal_path)?;
+        self.wal.sync_all()?;
+        self.wal = OpenOptions::new().append(true).open(&self.wal_path)?;
+        self.wal_broken = false;
+        self.records_since_snapshot = 0;
+        Ok(())
+    }
+}
+
+fn recover(snapshot_path: &Path, wal_path: &Path) -> io::Result<Snapshot> {
+    let mut snapshot = if snapshot_path.exists() {
+        serde_json::from_slice(&fs::read(snapshot_path)?)?
+    } else {
+        Snapshot::default()
+    };
+
+    if !wal_path.exists() {
+        return Ok(snapshot);
+    }
+
+    let lines: Vec<String> = BufReader::new(File::open(wal_path)?)
+        .lines()
+        .collect::<io::Result<_>>()?;
+    let mut replayed = 0;
+
+    for (index, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: WalEntry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            // A torn final line means the process died mid-write; that change
+            // was never acknowledged, so it is dropped.
+            Err(_) if index == lines.len() - 1 => {
+                warn!("Discarding incomplete record at end of {:?}", wal_path);
+                break;
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Corrupted record {} in {:?}: {}", index + 1, wal_path, e),
+                ));
+            }
+        };
+
+        if entry.seq <= snapshot.last_seq {
+            continue;
+        }
+        snapshot.apply(entry.record);
+        snapshot.last_seq = entry.seq;
+        replayed += 1;
+    }
+
+    info!(
+        "Recovered inventory from {:?}: {} products, {} log records replayed",
+        snapshot_path,
+        snapshot.products.len(),
+        replayed
+    );
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_models::{Currency, Money};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("cd-storage-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn product(quantity: u32) -> Product {
+        Product {
+            code: "P001".to_string(),
+            name: "Widget".to_string(),
+            price: Money::zero(Currency::BRL),
+            quantity: Some(quantity),
+        }
+    }
+
+    fn open(dir: &TempDir) -> InventoryStore {
+        let seed = HashMap::from([("P001".to_string(), product(10))]);
+        InventoryStore::open(&dir.0, seed, Vec::new()).unwrap()
+    }
+
+    fn set_quantity(store: &mut InventoryStore, quantity: u32) -> io::Result<()> {
+        store.upsert(
+            StockChange {
+                product: product(quantity),
+                reason: MovementReason::Adjustment,
+                counterparty_cd_id: None,
+                request_id: None,
+            },
+            None,
+        )
+    }
+
+    fn quantity(store: &InventoryStore) -> Option<u32> {
+        store.get("P001").and_then(|p| p.quantity)
+    }
+
+    fn append_to_wal(dir: &TempDir, bytes: &[u8]) {
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join(WAL_FILE))
+            .unwrap();
+        wal.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn recovers_committed_changes_after_restart() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        set_quantity(&mut store, 7).unwrap();
+        set_quantity(&mut store, 3).unwrap();
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!(quantity(&store), Some(3));
+        assert_eq!(store.ledger().len(), 3);
+    }
+
+    #[test]
+    fn discards_a_torn_last_record() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        set_quantity(&mut store, 7).unwrap();
+        drop(store);
+        append_to_wal(&dir, br#"{"seq":2,"op":"upsert","prod"#);
+
+        let store = open(&dir);
+        assert_eq!(quantity(&store), Some(7));
+    }
+
+    #[test]
+    fn refuses_a_corrupted_record_before_the_end() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        set_quantity(&mut store, 7).unwrap();
+        set_quantity(&mut store, 5).unwrap();
+        drop(store);
+        let valid = fs::read(dir.0.join(WAL_FILE)).unwrap();
+        fs::write(
+            dir.0.join(WAL_FILE),
+            [b"garbage\n".as_slice(), &valid].concat(),
+        )
+        .unwrap();
+
+        let error = InventoryStore::open(&dir.0, HashMap::new(), Vec::new())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replay_skips_records_already_in_the_snapshot() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        set_quantity(&mut store, 7).unwrap();
+        let before_compaction = fs::read(dir.0.join(WAL_FILE)).unwrap();
+        store.compact().unwrap();
+        set_quantity(&mut store, 4).unwrap();
+        drop(store);
+        // Crash between writing the snapshot and truncating the log: the log
+        // still starts with a record the snapshot already holds.
+        let after = fs::read(dir.0.join(WAL_FILE)).unwrap();
+        fs::write(dir.0.join(WAL_FILE), [before_compaction, after].concat()).unwrap();
+
+        let store = open(&dir);
+        assert_eq!(quantity(&store), Some(4));
+        assert_eq!(store.ledger().len(), 3);
+        assert_eq!(store.last_movement_id(), 3);
+    }
+
+    #[test]
+    fn compaction_moves_the_log_into_the_snapshot() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        set_quantity(&mut store, 7).unwrap();
+        store.compact().unwrap();
+        assert_eq!(fs::metadata(dir.0.join(WAL_FILE)).unwrap().len(), 0);
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!(quantity(&store), Some(7));
+    }
+
+    #[test]
+    fn failed_compaction_does_not_fail_the_change() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        // A directory where the temporary snapshot goes makes compaction fail.
+        fs::create_dir(store.snapshot_path.with_extension("json.tmp")).unwrap();
+        store.records_since_snapshot = COMPACT_AFTER_RECORDS;
+
+        set_quantity(&mut store, 7).unwrap();
+        assert_eq!(quantity(&store), Some(7));
+        let tmp_path = store.snapshot_path.with_extension("json.tmp");
+        drop(store);
+
+        fs::remove_dir(tmp_path).unwrap();
+        let store = open(&dir);
+        assert_eq!(quantity(&store), Some(7));
+    }
+
+    #[test]
+    fn committing_a_reservation_logs_the_transfer_with_its_quantity() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        let reservation = Reservation {
+            reservation_id: "r1".to_string(),
+            requester_cd_id: "cd_beta".to_string(),
+            product: product(0),
+            quantity: 4,
+            status: common_models::ReservationStatus::Reserved,
+            created_at: Utc::now(),
+            expires_at: Utc::now(),
+        };
+        let hold = StockChange {
+            product: product(6),
+            reason: MovementReason::ReservationHold,
+            counterparty_cd_id: Some("cd_beta".to_string()),
+            request_id: Some("r1".to_string()),
+        };
+        store
+            .put_reservation(reservation.clone(), Some(hold))
+            .unwrap();
+        store
+            .commit_reservation(Reservation {
+                status: common_models::ReservationStatus::Committed,
+                ..reservation
+            })
+            .unwrap();
+
+        assert_eq!(quantity(&store), Some(6));
+        let moves: Vec<(MovementReason, i64, u32)> = store
+            .movements_after(1)
+            .iter()
+            .map(|m| (m.reason, m.delta, m.quantity_after))
+            .collect();
+        assert_eq!(
+            moves,
+            vec![
+                (MovementReason::ReservationHold, -4, 6),
+                (MovementReason::ReservationRelease, 4, 10),
+                (MovementReason::TransferOut, -4, 6),
+            ]
+        );
+        drop(store);
+        assert_eq!(open(&dir).ledger().len(), 4);
+    }
+
+    #[test]
+    fn failed_append_is_not_applied_and_stops_further_writes() {
+        let dir = TempDir::new();
+        let mut store = open(&dir);
+        set_quantity(&mut store, 7).unwrap();
+        // A read-only handle makes both the write and its rollback fail.
+        store.wal = File::open(dir.0.join(WAL_FILE)).unwrap();
+
+        assert!(set_quantity(&mut store, 1).is_err());
+        assert_eq!(quantity(&store), Some(7));
+        assert!(store.wal_broken);
+        store.wal = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join(WAL_FILE))
+            .unwrap();
+        assert!(set_quantity(&mut store, 2).is_err());
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!(quantity(&store), Some(7));
+    }
+}
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Machine-readable kind of an `ApiError`. Each code always maps to the same
+/// HTTP status, so a caller can branch on either.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The request is malformed or fails validation.
+    BadRequest,
+    /// The request carries no valid credentials.
+    Unauthorized,
+    /// The caller is authenticated but not allowed to do this.
+    Forbidden,
+    NotFound,
+    AlreadyExists,
+    /// The request conflicts with the current state, e.g. a reused request id.
+    Conflict,
+    InsufficientStock,
+    ReservationExpired,
+    ReservationAborted,
+    /// The service is draining before shutdown and takes no new work.
+    ShuttingDown,
+    /// Another service needed for the answer could not be reached.
+    UpstreamUnavailable,
+    /// Another service answered with something unusable.
+    UpstreamError,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn http_status(self) -> u16 {
+        match self {
+            ErrorCode::BadRequest => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::AlreadyExists | ErrorCode::Conflict | ErrorCode::InsufficientStock => 409,
+            ErrorCode::ReservationExpired | ErrorCode::ReservationAborted => 410,
+            ErrorCode::Internal => 500,
+            ErrorCode::UpstreamError => 502,
+            ErrorCode::ShuttingDown | ErrorCode::UpstreamUnavailable => 503,
+        }
+    }
+}
+
+/// JSON body of every error response: `{"code": "NOT_FOUND", "message": "..."}`,
+/// with `details` when the caller needs more than the message to react.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// Prefixes the message with what was being done when the error happened.
+    pub fn context(mut self, context: &str) -> Self {
+        self.message = format!("{}: {}", context, self.message);
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = serde_json::to_value(self.code).map_err(|_| fmt::Error)?;
+        write!(
+            f,
+            "{} ({})",
+            self.message,
+            code.as_str().unwrap_or_default()
+        )
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[cfg(feature = "actix")]
+mod actix {
+    use super::{ApiError, ErrorCode};
+    use actix_web::http::StatusCode;
+    use actix_web::{HttpResponse, ResponseError, web};
+
+    impl ResponseError for ApiError {
+        fn status_code(&self) -> StatusCode {
+            StatusCode::from_u16(self.code.http_status())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+
+        fn error_response(&self) -> HttpResponse {
+            HttpResponse::build(self.status_code()).json(self)
+        }
+    }
+
+    /// Makes actix answer malformed JSON bodies, paths and query strings, and
+    /// unknown routes, with an `ApiError` body like the handlers do.
+    pub fn configure_error_responses(cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::JsonConfig::default().error_handler(|e, _| {
+            ApiError::new(ErrorCode::BadRequest, format!("Invalid JSON body: {}", e)).into()
+        }))
+        .app_data(web::PathConfig::default().error_handler(|e, _| {
+            ApiError::new(ErrorCode::BadRequest, format!("Invalid path: {}", e)).into()
+        }))
+        .app_data(web::QueryConfig::default().error_handler(|e, _| {
+            ApiError::new(ErrorCode::BadRequest, format!("Invalid query: {}", e)).into()
+        }))
+        .default_service(web::to(|| async {
+            ApiError::new(ErrorCode::NotFound, "No such endpoint").error_response()
+        }));
+    }
+}
+
+#[cfg(feature = "actix")]
+pub use actix::configure_error_responses;
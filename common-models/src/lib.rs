@@ -1,20 +1,58 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+mod error;
+mod money;
+mod report;
+
+#[cfg(feature = "actix")]
+pub use error::configure_error_responses;
+pub use error::{ApiError, ErrorCode};
+pub use money::{Currency, CurrencyMismatch, Money, MoneyError};
+pub use report::{
+    CdValuation, ProductValuation, RankBy, ReportFormat, ValuationQuery, ValuationReport,
+    total_by_currency,
+};
+pub use rust_decimal::Decimal;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Product {
     pub code: String,
     pub name: String,
-    pub price: f64,
+    pub price: Money,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub quantity: Option<u32>,
 }
 
+/// Kind of service behind a registration. Registrations without a type are
+/// CDs, the only services that registered before types existed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceType {
+    #[default]
+    Cd,
+    Hub,
+    Discovery,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServiceInfo {
     pub id: String,
     pub ip: String,
     pub port: u16,
+    /// `https` when the service listens with TLS.
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    #[serde(rename = "type", default)]
+    pub service_type: ServiceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Labels to select services by, such as `region:sul`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
     #[serde(default = "Utc::now")]
     pub last_heartbeat: DateTime<Utc>,
 }
@@ -24,9 +62,85 @@ pub struct ServiceInfoLookup {
     pub id: String,
     pub ip: String,
     pub port: u16,
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    #[serde(rename = "type", default)]
+    pub service_type: ServiceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl From<&ServiceInfo> for ServiceInfoLookup {
+    fn from(info: &ServiceInfo) -> Self {
+        ServiceInfoLookup {
+            id: info.id.clone(),
+            ip: info.ip.clone(),
+            port: info.port,
+            scheme: info.scheme.clone(),
+            service_type: info.service_type,
+            version: info.version.clone(),
+            tags: info.tags.clone(),
+            metadata: info.metadata.clone(),
+        }
+    }
+}
+
+/// Filters of `GET /lookup_all`, e.g. `?type=cd&tag=region:sul,transfers`.
+/// A service matches when it has the type and every tag listed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ServiceQuery {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub service_type: Option<ServiceType>,
+    /// Comma-separated tags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+impl ServiceQuery {
+    pub fn of_type(service_type: ServiceType) -> Self {
+        ServiceQuery {
+            service_type: Some(service_type),
+            tag: None,
+        }
+    }
+
+    pub fn matches(&self, info: &ServiceInfo) -> bool {
+        if self
+            .service_type
+            .is_some_and(|service_type| service_type != info.service_type)
+        {
+            return false;
+        }
+        self.tag.iter().flat_map(|tags| tags.split(',')).all(|tag| {
+            let tag = tag.trim();
+            tag.is_empty() || info.tags.iter().any(|t| t == tag)
+        })
+    }
+}
+
+/// Problems with the tags of a registration. Tags are matched whole and
+/// listed comma-separated in lookups, so they cannot be blank or hold commas.
+pub fn validate_tags(tags: &[String]) -> Vec<String> {
+    tags.iter()
+        .filter(|tag| tag.trim().is_empty() || tag.contains(',') || tag.trim() != tag.as_str())
+        .map(|tag| {
+            format!(
+                "tag {:?} must be non-empty, without commas or surrounding spaces",
+                tag
+            )
+        })
+        .collect()
+}
+
+fn default_scheme() -> String {
+    "http".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProductAvailability {
     pub cd_id: String,
     pub quantity_available: u32,
@@ -38,4 +152,253 @@ pub struct TransferRequest {
     pub product_code: String,
     pub quantity: u32,
     pub requester_cd_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReceiveRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub product: Product,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReservationRequest {
+    pub reservation_id: String,
+    pub product_code: String,
+    pub quantity: u32,
+    pub requester_cd_id: String,
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReservationStatus {
+    Reserved,
+    Committed,
+    Aborted,
+    Expired,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reservation {
+    pub reservation_id: String,
+    pub requester_cd_id: String,
+    pub product: Product,
+    pub quantity: u32,
+    pub status: ReservationStatus,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductRequest {
+    pub product_code: String,
+    pub quantity: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductRequestState {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductRequestStatus {
+    pub request_id: String,
+    pub product_code: String,
+    pub quantity: u32,
+    pub state: ProductRequestState,
+    pub quantity_received: u32,
+    pub progress: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Stock levels a CD keeps for one product: when the quantity falls below
+/// `min_quantity`, the CD requests enough from other CDs to get back to
+/// `target_quantity`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ReorderPolicy {
+    pub product_code: String,
+    pub min_quantity: u32,
+    pub target_quantity: u32,
+    /// Minimum time between two replenishments of this product; the CD's
+    /// default when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown_secs: Option<u64>,
+}
+
+/// Body of `PUT /reorder_policies/{product_code}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReorderLevels {
+    pub min_quantity: u32,
+    pub target_quantity: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown_secs: Option<u64>,
+}
+
+/// A reorder policy together with the current stock and the state of its
+/// automatic replenishment.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReorderPolicyStatus {
+    #[serde(flatten)]
+    pub policy: ReorderPolicy,
+    pub quantity: u32,
+    pub below_minimum: bool,
+    /// The product request currently restoring the stock, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_triggered_at: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+    /// No replenishment starts before this time, even below the minimum.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_after: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MovementReason {
+    TransferOut,
+    TransferIn,
+    Receive,
+    ReservationHold,
+    ReservationRelease,
+    Adjustment,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StockMovement {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub product_code: String,
+    pub delta: i64,
+    pub quantity_after: u32,
+    pub reason: MovementReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterparty_cd_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Partial update of a catalog product; absent fields keep their value.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProductPatch {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<Money>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<u32>,
+}
+
+/// One page of the hub catalog, ordered by product code.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductPage {
+    pub items: Vec<Product>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// One page of catalog search results. Pass `next_cursor` back as `cursor`
+/// to get the following page; it is absent on the last page.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductSearchPage {
+    pub items: Vec<Product>,
+    pub total_matches: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Stock of one product at one CD, as of the moment the hub learned it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CdStockLevel {
+    pub cd_id: String,
+    pub quantity: u32,
+    pub as_of: DateTime<Utc>,
+}
+
+/// Network-wide stock of one product with its per-CD breakdown.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductStock {
+    pub product_code: String,
+    pub name: String,
+    pub total_quantity: u64,
+    pub cds: Vec<CdStockLevel>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The CD failed repeatedly and the hub is not calling it for a while.
+    CircuitOpen,
+    TimedOut,
+    Failed,
+}
+
+/// A registered CD whose figures could not be included in a report.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UnavailableCd {
+    pub cd_id: String,
+    pub reason: SkipReason,
+    pub error: String,
+}
+
+/// Answer of `who_has_product`. `partial` is set when some registered CDs
+/// were skipped, in which case `cds` may be missing stock they hold.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AvailabilityResponse {
+    pub cds: Vec<ProductAvailability>,
+    pub partial: bool,
+    #[serde(default)]
+    pub skipped_cds: Vec<UnavailableCd>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StockOverview {
+    pub generated_at: DateTime<Utc>,
+    pub products: Vec<ProductStock>,
+    #[serde(default)]
+    pub unavailable_cds: Vec<UnavailableCd>,
+}
+
+/// New state of one product at a CD after a stock movement. `seq` is the id
+/// of that movement in the CD's ledger, so a CD's events are numbered 1, 2,
+/// 3... without gaps and the hub can tell when it missed one.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StockEvent {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub product: Product,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StockEventBatch {
+    pub cd_id: String,
+    pub events: Vec<StockEvent>,
+}
+
+/// Hub answer to a batch or snapshot: the last event it has applied for the
+/// CD, or `None` when it has no baseline and needs a snapshot first.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StockEventAck {
+    pub last_seq: Option<u64>,
+}
+
+/// Full inventory of a CD together with the last ledger movement it reflects.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InventorySnapshot {
+    pub cd_id: String,
+    pub seq: u64,
+    pub taken_at: DateTime<Utc>,
+    pub products: Vec<Product>,
 }
@@ -0,0 +1,393 @@
+use rust_decimal::Decimal;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// ISO 4217 currency code, such as `BRL`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const BRL: Currency = Currency(*b"BRL");
+
+    pub fn as_str(&self) -> &str {
+        // Only built from three ASCII uppercase letters.
+        std::str::from_utf8(&self.0).unwrap_or("???")
+    }
+}
+
+/// Prices written as plain numbers, as every price was before `Money`
+/// existed, are in this currency.
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::BRL
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code.as_bytes() {
+            &[a, b, c] if code.bytes().all(|b| b.is_ascii_uppercase()) => Ok(Currency([a, b, c])),
+            _ => Err(format!(
+                "currency must be a three-letter uppercase code, got {:?}",
+                code
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Amounts in different currencies were combined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrencyMismatch {
+    pub expected: Currency,
+    pub found: Currency,
+}
+
+impl fmt::Display for CurrencyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot combine amounts in {} and {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for CurrencyMismatch {}
+
+/// Arithmetic on `Money` that cannot give an exact result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoneyError {
+    CurrencyMismatch(CurrencyMismatch),
+    /// The result does not fit in a `Decimal`.
+    Overflow,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::CurrencyMismatch(e) => e.fmt(f),
+            MoneyError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+impl From<CurrencyMismatch> for MoneyError {
+    fn from(e: CurrencyMismatch) -> Self {
+        MoneyError::CurrencyMismatch(e)
+    }
+}
+
+/// An exact decimal amount of money in one currency.
+///
+/// Serialized as `{"amount": "2.50", "currency": "BRL"}`, with the amount as
+/// a string so no precision is lost. A plain JSON number (`2.5`) or numeric
+/// string (`"2.50"`) is also accepted and read as BRL.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Money {
+    amount: Decimal,
+    currency: Currency,
+}
+
+impl Money {
+    /// Highest unit price accepted for a product, so stock values of any
+    /// realistic quantity stay far from `Decimal::MAX`.
+    pub const MAX_PRICE: Decimal = Decimal::from_parts(1_000_000_000, 0, 0, false, 0);
+
+    pub fn new(amount: Decimal, currency: Currency) -> Self {
+        Money { amount, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Money::new(Decimal::ZERO, currency)
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.amount.is_sign_negative() && !self.amount.is_zero()
+    }
+
+    pub fn checked_add(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        self.amount
+            .checked_add(other.amount)
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        self.amount
+            .checked_sub(other.amount)
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// The price of `quantity` units at this unit price.
+    pub fn times(self, quantity: u64) -> Result<Money, MoneyError> {
+        self.amount
+            .checked_mul(Decimal::from(quantity))
+            .map(|amount| Money::new(amount, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// Adds up `amounts`, starting from zero in `currency`.
+    pub fn sum<I>(currency: Currency, amounts: I) -> Result<Money, MoneyError>
+    where
+        I: IntoIterator<Item = Money>,
+    {
+        amounts
+            .into_iter()
+            .try_fold(Money::zero(currency), Money::checked_add)
+    }
+
+    /// Checks this is usable as a unit price: not negative and at most
+    /// `MAX_PRICE`.
+    pub fn validate_price(&self) -> Result<(), String> {
+        if self.is_negative() {
+            Err("price must not be negative".to_string())
+        } else if self.amount > Money::MAX_PRICE {
+            Err(format!(
+                "price must not be greater than {}",
+                Money::MAX_PRICE
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rounds to the minor unit (cents), halves away from zero.
+    pub fn rounded(self) -> Money {
+        Money::new(
+            self.amount
+                .round_dp_with_strategy(2, rust_decimal::RoundingStrategy::MidpointAwayFromZero),
+            self.currency,
+        )
+    }
+
+    fn same_currency(&self, other: Money) -> Result<(), CurrencyMismatch> {
+        if self.currency == other.currency {
+            Ok(())
+        } else {
+            Err(CurrencyMismatch {
+                expected: self.currency,
+                found: other.currency,
+            })
+        }
+    }
+}
+
+/// Amounts in different currencies are not comparable.
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Money) -> Option<Ordering> {
+        if self.currency == other.currency {
+            Some(self.amount.cmp(&other.amount))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.currency, self.amount)
+    }
+}
+
+impl fmt::Debug for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Repr<'a> {
+            amount: String,
+            currency: &'a str,
+        }
+        Repr {
+            amount: self.amount.to_string(),
+            currency: self.currency.as_str(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+/// Reads an amount written as a number or a numeric string.
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number or a numeric string")
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+        // `f64`'s Display is the shortest text that reads back as the same
+        // number, so 2.5 becomes exactly 2.5 and not 2.4999999...
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        let text = value.trim();
+        Decimal::from_str(text)
+            .or_else(|_| Decimal::from_scientific(text))
+            .map_err(|_| E::custom(format!("invalid amount {:?}", value)))
+    }
+}
+
+struct Amount(Decimal);
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(AmountVisitor).map(Amount)
+    }
+}
+
+struct MoneyVisitor;
+
+impl<'de> Visitor<'de> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number, a numeric string or {\"amount\", \"currency\"}")
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Money, E> {
+        AmountVisitor
+            .visit_f64(value)
+            .map(|amount| Money::new(amount, Currency::default()))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Money, E> {
+        AmountVisitor
+            .visit_i64(value)
+            .map(|amount| Money::new(amount, Currency::default()))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Money, E> {
+        AmountVisitor
+            .visit_u64(value)
+            .map(|amount| Money::new(amount, Currency::default()))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Money, E> {
+        AmountVisitor
+            .visit_str(value)
+            .map(|amount| Money::new(amount, Currency::default()))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Money, A::Error> {
+        let mut amount = None;
+        let mut currency = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "amount" => amount = Some(map.next_value::<Amount>()?.0),
+                "currency" => currency = Some(map.next_value::<Currency>()?),
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+        let amount = amount.ok_or_else(|| de::Error::missing_field("amount"))?;
+        Ok(Money::new(amount, currency.unwrap_or_default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brl(amount: &str) -> Money {
+        Money::new(amount.parse().unwrap(), Currency::BRL)
+    }
+
+    #[test]
+    fn reads_numbers_strings_and_objects() {
+        let number: Money = serde_json::from_str("2.5").unwrap();
+        let string: Money = serde_json::from_str("\"2.50\"").unwrap();
+        let object: Money =
+            serde_json::from_str(r#"{"amount": "2.50", "currency": "USD"}"#).unwrap();
+        assert_eq!(number, brl("2.5"));
+        assert_eq!(string, brl("2.50"));
+        assert_eq!(object.amount(), "2.50".parse().unwrap());
+        assert_eq!(object.currency(), "USD".parse().unwrap());
+        assert!(serde_json::from_str::<Money>("\"abc\"").is_err());
+    }
+
+    #[test]
+    fn reads_floats_exactly() {
+        let money = MoneyVisitor.visit_f64::<de::value::Error>(2.5).unwrap();
+        assert_eq!(money.amount(), Decimal::new(25, 1));
+        assert_eq!(money.amount().to_string(), "2.5");
+
+        let money = MoneyVisitor.visit_f64::<de::value::Error>(0.1).unwrap();
+        assert_eq!(money.amount(), Decimal::new(1, 1));
+    }
+
+    #[test]
+    fn reports_overflow_instead_of_panicking() {
+        let max = Money::new(Decimal::MAX, Currency::BRL);
+        assert_eq!(max.checked_add(brl("1")), Err(MoneyError::Overflow));
+        assert_eq!(max.times(2), Err(MoneyError::Overflow));
+        assert!(matches!(
+            brl("1").checked_add(Money::zero("USD".parse().unwrap())),
+            Err(MoneyError::CurrencyMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+use super::{Currency, Money, MoneyError, UnavailableCd};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RankBy {
+    #[default]
+    Value,
+    Quantity,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Query string of the valuation endpoints: `?by=quantity&top=10&format=csv`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ValuationQuery {
+    #[serde(default)]
+    pub by: RankBy,
+    /// Keep only the first `top` products of the ranking, per currency when
+    /// ranking by value.
+    pub top: Option<usize>,
+    #[serde(default)]
+    pub format: ReportFormat,
+}
+
+/// Stock value of one product: `quantity` units at `unit_price`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductValuation {
+    pub product_code: String,
+    pub name: String,
+    pub quantity: u64,
+    pub unit_price: Money,
+    pub value: Money,
+}
+
+impl ProductValuation {
+    pub fn new(
+        product_code: String,
+        name: String,
+        quantity: u64,
+        unit_price: Money,
+    ) -> Result<Self, MoneyError> {
+        Ok(ProductValuation {
+            value: unit_price.times(quantity)?,
+            product_code,
+            name,
+            quantity,
+            unit_price,
+        })
+    }
+}
+
+/// Stock value held by one CD, in the hub's network-wide report.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CdValuation {
+    pub cd_id: String,
+    pub total_quantity: u64,
+    pub total_value: Vec<Money>,
+}
+
+/// Inventory valuation of one CD (`cd_id` set) or of the whole network.
+///
+/// Totals cover every product, even when `products` is cut to the top N.
+/// `total_value` has one entry per currency the prices are in.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ValuationReport {
+    pub generated_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cd_id: Option<String>,
+    pub ranked_by: RankBy,
+    pub total_quantity: u64,
+    pub total_value: Vec<Money>,
+    pub products: Vec<ProductValuation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cds: Vec<CdValuation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_cds: Vec<UnavailableCd>,
+}
+
+impl ValuationReport {
+    /// Computes the totals of `products`, then ranks them and keeps the top
+    /// ones as asked by `query`. Values in different currencies are not
+    /// comparable, so a ranking by value groups products by currency and
+    /// ranks each group on its own.
+    pub fn new(
+        cd_id: Option<String>,
+        mut products: Vec<ProductValuation>,
+        query: &ValuationQuery,
+    ) -> Result<Self, MoneyError> {
+        let total_quantity = products.iter().map(|p| p.quantity).sum();
+        let total_value = total_by_currency(products.iter().map(|p| p.value))?;
+
+        match query.by {
+            RankBy::Value => products.sort_by(|a, b| {
+                a.value
+                    .currency()
+                    .cmp(&b.value.currency())
+                    .then_with(|| b.value.amount().cmp(&a.value.amount()))
+                    .then_with(|| a.product_code.cmp(&b.product_code))
+            }),
+            RankBy::Quantity => products.sort_by(|a, b| {
+                b.quantity
+                    .cmp(&a.quantity)
+                    .then_with(|| a.product_code.cmp(&b.product_code))
+            }),
+        }
+        match (query.top, query.by) {
+            (Some(top), RankBy::Value) => {
+                let mut kept: BTreeMap<Currency, usize> = BTreeMap::new();
+                products.retain(|p| {
+                    let kept = kept.entry(p.value.currency()).or_default();
+                    *kept += 1;
+                    *kept <= top
+                });
+            }
+            (Some(top), RankBy::Quantity) => products.truncate(top),
+            (None, _) => {}
+        }
+
+        Ok(ValuationReport {
+            generated_at: Utc::now(),
+            cd_id,
+            ranked_by: query.by,
+            total_quantity,
+            total_value,
+            products,
+            cds: Vec::new(),
+            unavailable_cds: Vec::new(),
+        })
+    }
+
+    /// One line per product, with a header:
+    /// `product_code,name,quantity,unit_price,value,currency`.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("product_code,name,quantity,unit_price,value,currency\n");
+        for p in &self.products {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{}",
+                csv_field(&p.product_code),
+                csv_field(&p.name),
+                p.quantity,
+                p.unit_price.amount(),
+                p.value.amount(),
+                p.value.currency()
+            );
+        }
+        csv
+    }
+}
+
+/// Sums `amounts` separately for each currency, ordered by currency code.
+pub fn total_by_currency(
+    amounts: impl IntoIterator<Item = Money>,
+) -> Result<Vec<Money>, MoneyError> {
+    let mut totals: BTreeMap<Currency, Money> = BTreeMap::new();
+    for amount in amounts {
+        let total = totals
+            .entry(amount.currency())
+            .or_insert_with(|| Money::zero(amount.currency()));
+        *total = total.checked_add(amount)?;
+    }
+    Ok(totals.into_values().collect())
+}
+
+/// Quotes a CSV field when it contains a separator, a quote or a line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(feature = "actix")]
+impl ValuationReport {
+    /// The report as JSON, or as a CSV download named after its scope and date.
+    pub fn respond(&self, format: ReportFormat) -> actix_web::HttpResponse {
+        match format {
+            ReportFormat::Json => actix_web::HttpResponse::Ok().json(self),
+            ReportFormat::Csv => {
+                let filename = format!(
+                    "valuation-{}-{}.csv",
+                    self.cd_id.as_deref().unwrap_or("network"),
+                    self.generated_at.format("%Y%m%d%H%M%S")
+                );
+                actix_web::HttpResponse::Ok()
+                    .content_type("text/csv; charset=utf-8")
+                    .insert_header((
+                        "Content-Disposition",
+                        format!("attachment; filename=\"{}\"", filename),
+                    ))
+                    .body(self.to_csv())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brl() -> Currency {
+        Currency::BRL
+    }
+
+    fn usd() -> Currency {
+        "USD".parse().unwrap()
+    }
+
+    fn valuation(code: &str, quantity: u64, price: &str, currency: Currency) -> ProductValuation {
+        let price = Money::new(price.parse().unwrap(), currency);
+        ProductValuation::new(code.to_string(), code.to_string(), quantity, price).unwrap()
+    }
+
+    fn sample() -> Vec<ProductValuation> {
+        vec![
+            valuation("garrafas", 50, "2.50", brl()),
+            valuation("celulares", 10, "1200.00", brl()),
+            valuation("cabos", 200, "3.00", usd()),
+            valuation("capas", 30, "20.00", brl()),
+            valuation("fones", 5, "80.00", usd()),
+        ]
+    }
+
+    fn report(query: ValuationQuery) -> ValuationReport {
+        ValuationReport::new(None, sample(), &query).unwrap()
+    }
+
+    fn codes(report: &ValuationReport) -> Vec<&str> {
+        report
+            .products
+            .iter()
+            .map(|p| p.product_code.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn ranks_by_value_within_each_currency() {
+        let report = report(ValuationQuery::default());
+        assert_eq!(
+            codes(&report),
+            vec!["celulares", "capas", "garrafas", "cabos", "fones"]
+        );
+        assert_eq!(
+            report.total_value,
+            vec![
+                Money::new("12725.00".parse().unwrap(), brl()),
+                Money::new("1000.00".parse().unwrap(), usd()),
+            ]
+        );
+        assert_eq!(report.total_quantity, 295);
+    }
+
+    #[test]
+    fn top_by_value_keeps_n_per_currency() {
+        let report = report(ValuationQuery {
+            top: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(codes(&report), vec!["celulares", "cabos"]);
+        // Os totais continuam cobrindo todos os produtos.
+        assert_eq!(report.total_quantity, 295);
+        assert_eq!(report.total_value.len(), 2);
+    }
+
+    #[test]
+    fn top_by_quantity_ignores_currency() {
+        let report = report(ValuationQuery {
+            by: RankBy::Quantity,
+            top: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(codes(&report), vec!["cabos", "garrafas", "capas"]);
+    }
+
+    #[test]
+    fn ties_are_broken_by_product_code() {
+        let products = vec![
+            valuation("b", 10, "1.00", brl()),
+            valuation("a", 10, "1.00", brl()),
+        ];
+        for by in [RankBy::Value, RankBy::Quantity] {
+            let query = ValuationQuery {
+                by,
+                ..Default::default()
+            };
+            let report = ValuationReport::new(None, products.clone(), &query).unwrap();
+            assert_eq!(codes(&report), vec!["a", "b"]);
+        }
+    }
+
+    #[test]
+    fn csv_quotes_commas_quotes_and_line_breaks() {
+        let mut product = valuation("p1", 2, "1.50", brl());
+        product.name = "Cabo \"USB\", 2m".to_string();
+        let mut other = valuation("p2", 1, "3.00", brl());
+        other.name = "Linha 1\nLinha 2".to_string();
+        let report =
+            ValuationReport::new(None, vec![product, other], &ValuationQuery::default()).unwrap();
+
+        assert_eq!(
+            report.to_csv(),
+            "product_code,name,quantity,unit_price,value,currency\n\
+             p1,\"Cabo \"\"USB\"\", 2m\",2,1.50,3.00,BRL\n\
+             p2,\"Linha 1\nLinha 2\",1,3.00,3.00,BRL\n"
+        );
+    }
+
+    #[test]
+    fn plain_fields_are_not_quoted() {
+        assert_eq!(csv_field("garrafas"), "garrafas");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a\rb"), "\"a\rb\"");
+    }
+}
@@ -18,11 +18,11 @@ Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin s
 Start-Sleep -Seconds 3
 Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin hub-service" -WindowStyle Minimized
 Start-Sleep -Seconds 3
-Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service cd_alpha 8083" -WindowStyle Minimized
+Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service -- --config config/cd_alpha.toml" -WindowStyle Minimized
 Start-Sleep -Seconds 2
-Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service cd_beta 8084" -WindowStyle Minimized
+Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service -- --config config/cd_beta.toml" -WindowStyle Minimized
 Start-Sleep -Seconds 2
-Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service cd_gamma 8085" -WindowStyle Minimized
+Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service -- --config config/cd_gamma.toml" -WindowStyle Minimized
 Start-Sleep -Seconds 5
 
 Write-Host "Aguardando serviços estarem prontos..." -ForegroundColor Yellow
@@ -69,11 +69,13 @@ try {
         price = 2500.00
         description = "Produto demonstrado na apresentação"
     }
+    # O catálogo persiste entre execuções; remove o produto de uma execução anterior
+    try { Invoke-RestMethod -Uri "http://127.0.0.1:8082/products/laptop_demo" -Method DELETE } catch {}
     Invoke-RestMethod -Uri "http://127.0.0.1:8082/products" -Method POST -Body ($product | ConvertTo-Json) -ContentType "application/json"
     Write-Host "   ✓ Produto registrado no Hub" -ForegroundColor Green
     
     $product_info = Invoke-RestMethod -Uri "http://127.0.0.1:8082/products/laptop_demo" -Method GET
-    Write-Host "   ✓ Produto consultado: $($product_info.name) - R$ $($product_info.price)" -ForegroundColor Green
+    Write-Host "   ✓ Produto consultado: $($product_info.name) - R$ $($product_info.price.amount)" -ForegroundColor Green
 } catch {
     Write-Host "   ✗ Erro ao testar catálogo" -ForegroundColor Red
 }
@@ -84,8 +86,8 @@ Write-Host "   O Hub consulta todos os CDs para encontrar produtos" -ForegroundC
 
 try {
     $who_has = Invoke-RestMethod -Uri "http://127.0.0.1:8082/who_has_product/celulares/5" -Method GET
-    Write-Host "   ✓ CDs com celulares encontrados: $($who_has.Count)" -ForegroundColor Green
-    foreach ($cd in $who_has) {
+    Write-Host "   ✓ CDs com celulares encontrados: $($who_has.cds.Count)" -ForegroundColor Green
+    foreach ($cd in $who_has.cds) {
         Write-Host "     - $($cd.cd_id): $($cd.quantity_available) unidades" -ForegroundColor White
     }
 } catch {
@@ -97,9 +99,22 @@ Write-Host "6. DEMONSTRAÇÃO: TRANSFERÊNCIA DE PRODUTOS" -ForegroundColor Yell
 Write-Host "   CD Alpha vai solicitar canetas (que ele não tem)" -ForegroundColor White
 Write-Host "   O sistema deve encontrar CD Gamma que tem canetas" -ForegroundColor White
 
+try {
+    $product_request = @{
+        product_code = "canetas"
+        quantity = 20
+    }
+    $request_status = Invoke-RestMethod -Uri "http://127.0.0.1:8083/requests" -Method POST -Body ($product_request | ConvertTo-Json) -ContentType "application/json"
+    Write-Host "   ✓ Pedido criado: $($request_status.request_id)" -ForegroundColor Green
+} catch {
+    Write-Host "   ✗ Erro ao criar pedido" -ForegroundColor Red
+}
+
 Start-Sleep -Seconds 3
 
 try {
+    $request_status = Invoke-RestMethod -Uri "http://127.0.0.1:8083/requests/$($request_status.request_id)" -Method GET
+    Write-Host "   ✓ Estado do pedido: $($request_status.state) - $($request_status.progress)" -ForegroundColor Green
     $cd_alpha_canetas = Invoke-RestMethod -Uri "http://127.0.0.1:8083/inventory/canetas" -Method GET
     if ($cd_alpha_canetas.quantity -gt 0) {
         Write-Host "   ✓ Transferência bem-sucedida! CD Alpha agora tem $($cd_alpha_canetas.quantity) canetas" -ForegroundColor Green
@@ -137,14 +152,17 @@ Write-Host "  POST /register" -ForegroundColor Gray
 Write-Host "  GET /lookup/{id}" -ForegroundColor Gray
 Write-Host ""
 Write-Host "Hub Service (8082):" -ForegroundColor White
-Write-Host "  POST /products" -ForegroundColor Gray
-Write-Host "  GET /products/{code}" -ForegroundColor Gray
+Write-Host "  GET, POST /products" -ForegroundColor Gray
+Write-Host "  GET, PUT, PATCH, DELETE /products/{code}" -ForegroundColor Gray
 Write-Host "  GET /who_has_product/{code}/{quantity}" -ForegroundColor Gray
+Write-Host "  GET /stock, GET /stock/{code}" -ForegroundColor Gray
 Write-Host ""
 Write-Host "CD Services (8083, 8084, 8085):" -ForegroundColor White
 Write-Host "  GET /inventory/{product_code}" -ForegroundColor Gray
 Write-Host "  POST /transfer_product" -ForegroundColor Gray
 Write-Host "  POST /receive_product" -ForegroundColor Gray
+Write-Host "  POST /requests" -ForegroundColor Gray
+Write-Host "  GET /requests/{id}" -ForegroundColor Gray
 Write-Host ""
 Write-Host "=== DEMONSTRAÇÃO CONCLUÍDA ===" -ForegroundColor Green
 Write-Host "Todos os serviços continuam rodando para testes adicionais" -ForegroundColor Yellow
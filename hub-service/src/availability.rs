@@ -0,0 +1,252 @@
+use chrono::{DateTime, Utc};
+use common_models::{InventorySnapshot, Product, StockEventBatch};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// What the hub knows about one CD's stock.
+struct CdStock {
+    last_seq: u64,
+    last_contact: DateTime<Utc>,
+    products: HashMap<String, Product>,
+}
+
+/// Stock of every CD, materialized from the snapshots and events the CDs
+/// push, so availability queries do not have to call the CDs.
+#[derive(Default)]
+pub struct AvailabilityIndex {
+    cds: HashMap<String, CdStock>,
+    holders: HashMap<String, HashSet<String>>,
+}
+
+impl AvailabilityIndex {
+    /// Replaces everything known about the CD with `snapshot`.
+    pub fn apply_snapshot(&mut self, snapshot: InventorySnapshot) -> u64 {
+        self.forget(&snapshot.cd_id);
+        let mut cd = CdStock {
+            last_seq: snapshot.seq,
+            last_contact: Utc::now(),
+            products: HashMap::new(),
+        };
+        for product in snapshot.products {
+            self.set_quantity(&snapshot.cd_id, &mut cd, product);
+        }
+        self.cds.insert(snapshot.cd_id, cd);
+        snapshot.seq
+    }
+
+    /// Applies the events that follow the last one already applied and skips
+    /// those seen before. `Err` carries the last applied event (`None` if the
+    /// CD has no baseline) when the batch leaves a gap, so the CD can resend
+    /// from there or send a snapshot.
+    pub fn apply_events(&mut self, batch: StockEventBatch) -> Result<u64, Option<u64>> {
+        let Some(mut cd) = self.cds.remove(&batch.cd_id) else {
+            return Err(None);
+        };
+        cd.last_contact = Utc::now();
+
+        let mut result = Ok(cd.last_seq);
+        for event in batch.events {
+            if event.seq <= cd.last_seq {
+                continue;
+            }
+            if event.seq != cd.last_seq + 1 {
+                result = Err(Some(cd.last_seq));
+                break;
+            }
+            cd.last_seq = event.seq;
+            self.set_quantity(&batch.cd_id, &mut cd, event.product);
+            result = Ok(cd.last_seq);
+        }
+        self.cds.insert(batch.cd_id, cd);
+        result
+    }
+
+    pub fn has_baseline(&self, cd_id: &str) -> bool {
+        self.cds.contains_key(cd_id)
+    }
+
+    /// Drops the stock of CDs that are no longer registered.
+    pub fn retain_cds(&mut self, registered: &HashSet<String>) {
+        let gone: Vec<String> = self
+            .cds
+            .keys()
+            .filter(|cd_id| !registered.contains(*cd_id))
+            .cloned()
+            .collect();
+        for cd_id in gone {
+            info!("Dropping stock of unregistered CD {}", cd_id);
+            self.forget(&cd_id);
+        }
+    }
+
+    /// CDs holding `product_code` with what they hold and when the hub last
+    /// heard from them.
+    pub fn holders(&self, product_code: &str) -> Vec<(String, Product, DateTime<Utc>)> {
+        let Some(cd_ids) = self.holders.get(product_code) else {
+            return Vec::new();
+        };
+        cd_ids
+            .iter()
+            .filter_map(|cd_id| {
+                let cd = self.cds.get(cd_id)?;
+                let product = cd.products.get(product_code)?;
+                Some((cd_id.clone(), product.clone(), cd.last_contact))
+            })
+            .collect()
+    }
+
+    pub fn product_codes(&self) -> impl Iterator<Item = &String> {
+        self.holders.keys()
+    }
+
+    fn set_quantity(&mut self, cd_id: &str, cd: &mut CdStock, product: Product) {
+        let holders = self.holders.entry(product.code.clone()).or_default();
+        if product.quantity.unwrap_or(0) > 0 {
+            holders.insert(cd_id.to_string());
+        } else {
+            holders.remove(cd_id);
+        }
+        if holders.is_empty() {
+            self.holders.remove(&product.code);
+        }
+        cd.products.insert(product.code.clone(), product);
+    }
+
+    fn forget(&mut self, cd_id: &str) {
+        if let Some(cd) = self.cds.remove(cd_id) {
+            for code in cd.products.keys() {
+                if let Some(holders) = self.holders.get_mut(code) {
+                    holders.remove(cd_id);
+                    if holders.is_empty() {
+                        self.holders.remove(code);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_models::{Currency, Money, StockEvent};
+
+    fn product(code: &str, quantity: u32) -> Product {
+        Product {
+            code: code.to_string(),
+            name: code.to_string(),
+            price: Money::zero(Currency::BRL),
+            quantity: Some(quantity),
+        }
+    }
+
+    fn snapshot(cd_id: &str, seq: u64, products: Vec<Product>) -> InventorySnapshot {
+        InventorySnapshot {
+            cd_id: cd_id.to_string(),
+            seq,
+            taken_at: Utc::now(),
+            products,
+        }
+    }
+
+    fn batch(cd_id: &str, events: &[(u64, u32)]) -> StockEventBatch {
+        StockEventBatch {
+            cd_id: cd_id.to_string(),
+            events: events
+                .iter()
+                .map(|&(seq, quantity)| StockEvent {
+                    seq,
+                    timestamp: Utc::now(),
+                    product: product("P1", quantity),
+                })
+                .collect(),
+        }
+    }
+
+    fn quantity_at(index: &AvailabilityIndex, cd_id: &str) -> Option<u32> {
+        index
+            .holders("P1")
+            .into_iter()
+            .find(|(id, _, _)| id == cd_id)
+            .and_then(|(_, product, _)| product.quantity)
+    }
+
+    #[test]
+    fn events_without_a_snapshot_ask_for_one() {
+        let mut index = AvailabilityIndex::default();
+        assert_eq!(index.apply_events(batch("cd_alpha", &[(1, 5)])), Err(None));
+        assert!(!index.has_baseline("cd_alpha"));
+    }
+
+    #[test]
+    fn consecutive_events_are_applied() {
+        let mut index = AvailabilityIndex::default();
+        index.apply_snapshot(snapshot("cd_alpha", 3, vec![product("P1", 10)]));
+
+        assert_eq!(
+            index.apply_events(batch("cd_alpha", &[(4, 8), (5, 6)])),
+            Ok(5)
+        );
+        assert_eq!(quantity_at(&index, "cd_alpha"), Some(6));
+    }
+
+    #[test]
+    fn repeated_events_are_skipped() {
+        let mut index = AvailabilityIndex::default();
+        index.apply_snapshot(snapshot("cd_alpha", 3, vec![product("P1", 10)]));
+        index.apply_events(batch("cd_alpha", &[(4, 8)])).unwrap();
+
+        // O 4 já foi aplicado: reenviá-lo não pode voltar o estoque para 8.
+        assert_eq!(
+            index.apply_events(batch("cd_alpha", &[(3, 1), (4, 8), (5, 7)])),
+            Ok(5)
+        );
+        assert_eq!(quantity_at(&index, "cd_alpha"), Some(7));
+        assert_eq!(index.apply_events(batch("cd_alpha", &[(5, 7)])), Ok(5));
+        assert_eq!(quantity_at(&index, "cd_alpha"), Some(7));
+    }
+
+    #[test]
+    fn a_gap_keeps_the_events_before_it_and_reports_the_last_applied() {
+        let mut index = AvailabilityIndex::default();
+        index.apply_snapshot(snapshot("cd_alpha", 3, vec![product("P1", 10)]));
+
+        assert_eq!(
+            index.apply_events(batch("cd_alpha", &[(4, 9), (6, 2), (7, 1)])),
+            Err(Some(4))
+        );
+        assert_eq!(quantity_at(&index, "cd_alpha"), Some(9));
+        assert_eq!(index.apply_events(batch("cd_alpha", &[(5, 4)])), Ok(5));
+    }
+
+    #[test]
+    fn an_empty_batch_acks_the_last_event() {
+        let mut index = AvailabilityIndex::default();
+        index.apply_snapshot(snapshot("cd_alpha", 3, vec![product("P1", 10)]));
+        assert_eq!(index.apply_events(batch("cd_alpha", &[])), Ok(3));
+    }
+
+    #[test]
+    fn a_snapshot_replaces_what_was_known_about_the_cd() {
+        let mut index = AvailabilityIndex::default();
+        index.apply_snapshot(snapshot("cd_alpha", 10, vec![product("P1", 10)]));
+        index.apply_snapshot(snapshot("cd_beta", 1, vec![product("P1", 3)]));
+
+        // Depois de reiniciar, o CD volta com uma sequência menor e sem o P1.
+        assert_eq!(index.apply_snapshot(snapshot("cd_alpha", 2, Vec::new())), 2);
+        assert_eq!(quantity_at(&index, "cd_alpha"), None);
+        assert_eq!(quantity_at(&index, "cd_beta"), Some(3));
+        assert_eq!(index.apply_events(batch("cd_alpha", &[(3, 4)])), Ok(3));
+        assert_eq!(quantity_at(&index, "cd_alpha"), Some(4));
+    }
+
+    #[test]
+    fn products_out_of_stock_have_no_holders() {
+        let mut index = AvailabilityIndex::default();
+        index.apply_snapshot(snapshot("cd_alpha", 1, vec![product("P1", 10)]));
+        index.apply_events(batch("cd_alpha", &[(2, 0)])).unwrap();
+
+        assert!(index.holders("P1").is_empty());
+        assert_eq!(index.product_codes().count(), 0);
+    }
+}
@@ -0,0 +1,144 @@
+use super::search::{SearchIndex, SearchQuery};
+use common_models::{Product, ProductPatch, ProductSearchPage};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const CATALOG_FILE: &str = "catalog.json";
+
+#[derive(Debug)]
+pub enum CatalogError {
+    AlreadyExists,
+    NotFound,
+    Io(io::Error),
+}
+
+impl From<io::Error> for CatalogError {
+    fn from(e: io::Error) -> Self {
+        CatalogError::Io(e)
+    }
+}
+
+/// Hub product catalog, kept sorted by code and written to disk on every
+/// change. Catalog writes are rare, so the whole file is rewritten each time.
+pub struct CatalogStore {
+    products: BTreeMap<String, Product>,
+    index: SearchIndex,
+    path: PathBuf,
+}
+
+impl CatalogStore {
+    /// Loads `catalog.json` from `data_dir`, or starts empty if there is none.
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(CATALOG_FILE);
+        let products = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        let mut index = SearchIndex::default();
+        for product in products.values() {
+            index.insert(product);
+        }
+        Ok(CatalogStore {
+            products,
+            index,
+            path,
+        })
+    }
+
+    pub fn get(&self, code: &str) -> Option<&Product> {
+        self.products.get(code)
+    }
+
+    pub fn len(&self) -> usize {
+        self.products.len()
+    }
+
+    pub fn page(&self, offset: usize, limit: usize) -> Vec<Product> {
+        self.products
+            .values()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn create(&mut self, product: Product) -> Result<(), CatalogError> {
+        if self.products.contains_key(&product.code) {
+            return Err(CatalogError::AlreadyExists);
+        }
+        self.commit(product.code.clone(), Some(product))
+    }
+
+    pub fn replace(&mut self, product: Product) -> Result<(), CatalogError> {
+        if !self.products.contains_key(&product.code) {
+            return Err(CatalogError::NotFound);
+        }
+        self.commit(product.code.clone(), Some(product))
+    }
+
+    /// Returns the patched product as it would be stored, without storing it,
+    /// so the caller can validate the result first.
+    pub fn patched(&self, code: &str, patch: &ProductPatch) -> Result<Product, CatalogError> {
+        let mut product = self
+            .products
+            .get(code)
+            .cloned()
+            .ok_or(CatalogError::NotFound)?;
+        if let Some(name) = &patch.name {
+            product.name = name.clone();
+        }
+        if let Some(price) = patch.price {
+            product.price = price;
+        }
+        if patch.quantity.is_some() {
+            product.quantity = patch.quantity;
+        }
+        Ok(product)
+    }
+
+    pub fn delete(&mut self, code: &str) -> Result<Product, CatalogError> {
+        let product = self
+            .products
+            .get(code)
+            .cloned()
+            .ok_or(CatalogError::NotFound)?;
+        self.commit(code.to_string(), None)?;
+        Ok(product)
+    }
+
+    pub fn search(&self, query: &SearchQuery) -> Result<ProductSearchPage, String> {
+        self.index.search(&self.products, query)
+    }
+
+    /// Saves the catalog with `code` set to `product` (or removed) and only
+    /// then applies the change in memory, so a failed write leaves memory,
+    /// disk and the search index in agreement.
+    fn commit(&mut self, code: String, product: Option<Product>) -> Result<(), CatalogError> {
+        let mut products = self.products.clone();
+        match &product {
+            Some(product) => products.insert(code.clone(), product.clone()),
+            None => products.remove(&code),
+        };
+
+        let tmp_path = self.path.with_extension("json.tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&serde_json::to_vec_pretty(&products).map_err(io::Error::from)?)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        if let Some(old) = self.products.get(&code) {
+            self.index.remove(old);
+        }
+        if let Some(product) = &product {
+            self.index.insert(product);
+        }
+        self.products = products;
+        Ok(())
+    }
+}
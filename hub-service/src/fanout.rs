@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Limits for the hub's calls to CDs, read from the environment.
+#[derive(Debug, Clone)]
+pub struct FanoutConfig {
+    /// Deadline for one HTTP attempt (`HUB_CD_TIMEOUT_MS`, default 2000).
+    pub request_timeout: Duration,
+    /// Extra attempts after the first one fails (`HUB_CD_RETRIES`, default 2).
+    pub retries: u32,
+    /// Consecutive failed calls that open a CD's circuit
+    /// (`HUB_CD_BREAKER_THRESHOLD`, default 3).
+    pub breaker_threshold: u32,
+    /// How long an open circuit skips the CD before one probe call is let
+    /// through (`HUB_CD_BREAKER_OPEN_SECS`, default 30).
+    pub breaker_open_for: Duration,
+}
+
+fn env_or<T: FromStr>(name: &str, default: T, problems: &mut Vec<String>) -> T {
+    match env::var(name) {
+        Ok(raw) => raw.trim().parse().unwrap_or_else(|_| {
+            problems.push(format!("{} {:?} is not a valid number", name, raw));
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+impl FanoutConfig {
+    pub fn from_env() -> Result<Self, Vec<String>> {
+        let mut problems = Vec::new();
+        let timeout_ms: u64 = env_or("HUB_CD_TIMEOUT_MS", 2000, &mut problems);
+        let retries: u32 = env_or("HUB_CD_RETRIES", 2, &mut problems);
+        let breaker_threshold: u32 = env_or("HUB_CD_BREAKER_THRESHOLD", 3, &mut problems);
+        let open_secs: u64 = env_or("HUB_CD_BREAKER_OPEN_SECS", 30, &mut problems);
+
+        if timeout_ms == 0 {
+            problems.push("HUB_CD_TIMEOUT_MS must be greater than zero".to_string());
+        }
+        if breaker_threshold == 0 {
+            problems.push("HUB_CD_BREAKER_THRESHOLD must be greater than zero".to_string());
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        Ok(FanoutConfig {
+            request_timeout: Duration::from_millis(timeout_ms),
+            retries,
+            breaker_threshold,
+            breaker_open_for: Duration::from_secs(open_secs),
+        })
+    }
+
+    /// Longest a call to a CD can take, counting every attempt and the
+    /// backoff between them.
+    pub fn call_budget(&self) -> Duration {
+        let backoff =
+            Duration::from_millis(100u64.saturating_mul(2u64.saturating_pow(self.retries)));
+        (self.request_timeout + backoff).saturating_mul(self.retries + 1)
+    }
+}
+
+enum Breaker {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// The open period is over and one probe call is in flight. A probe
+    /// still unsettled at `until` was cancelled, and another one may go.
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+/// One circuit breaker per CD id. A CD whose calls keep failing is skipped
+/// for a while instead of making every query wait for its timeouts.
+pub struct CircuitBreakers {
+    threshold: u32,
+    open_for: Duration,
+    probe_for: Duration,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: &FanoutConfig) -> Self {
+        CircuitBreakers {
+            threshold: config.breaker_threshold,
+            open_for: config.breaker_open_for,
+            probe_for: config.call_budget(),
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a call to `cd_id` may go ahead now.
+    pub fn allow(&self, cd_id: &str) -> bool {
+        self.allow_at(cd_id, Instant::now())
+    }
+
+    fn allow_at(&self, cd_id: &str, now: Instant) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        match breakers.get(cd_id) {
+            None | Some(Breaker::Closed { .. }) => true,
+            Some(Breaker::Open { until } | Breaker::HalfOpen { until }) if now >= *until => {
+                breakers.insert(
+                    cd_id.to_string(),
+                    Breaker::HalfOpen {
+                        until: now + self.probe_for,
+                    },
+                );
+                true
+            }
+            Some(Breaker::Open { .. } | Breaker::HalfOpen { .. }) => false,
+        }
+    }
+
+    pub fn record_success(&self, cd_id: &str) {
+        let mut breakers = self.breakers.lock().unwrap();
+        if let Some(Breaker::HalfOpen { .. } | Breaker::Open { .. }) = breakers.get(cd_id) {
+            info!("Circuit for CD {} closed", cd_id);
+        }
+        breakers.insert(cd_id.to_string(), Breaker::Closed { failures: 0 });
+    }
+
+    pub fn record_failure(&self, cd_id: &str) {
+        self.record_failure_at(cd_id, Instant::now())
+    }
+
+    fn record_failure_at(&self, cd_id: &str, now: Instant) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let failures = match breakers.get(cd_id) {
+            Some(Breaker::Closed { failures }) => failures + 1,
+            None => 1,
+            Some(Breaker::HalfOpen { .. } | Breaker::Open { .. }) => self.threshold,
+        };
+        let next = if failures >= self.threshold {
+            warn!("Circuit for CD {} open for {:?}", cd_id, self.open_for);
+            Breaker::Open {
+                until: now + self.open_for,
+            }
+        } else {
+            Breaker::Closed { failures }
+        };
+        breakers.insert(cd_id.to_string(), next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPEN_FOR: Duration = Duration::from_secs(30);
+    const PROBE_FOR: Duration = Duration::from_secs(5);
+
+    fn breakers() -> CircuitBreakers {
+        CircuitBreakers {
+            threshold: 3,
+            open_for: OPEN_FOR,
+            probe_for: PROBE_FOR,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn fail(breakers: &CircuitBreakers, times: u32, now: Instant) {
+        for _ in 0..times {
+            breakers.record_failure_at("cd_alpha", now);
+        }
+    }
+
+    #[test]
+    fn circuit_opens_after_threshold_failures_in_a_row() {
+        let breakers = breakers();
+        let now = Instant::now();
+
+        fail(&breakers, 2, now);
+        assert!(breakers.allow_at("cd_alpha", now));
+        fail(&breakers, 1, now);
+        assert!(!breakers.allow_at("cd_alpha", now));
+        assert!(!breakers.allow_at("cd_alpha", now + OPEN_FOR - Duration::from_millis(1)));
+        assert!(breakers.allow_at("cd_beta", now));
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let breakers = breakers();
+        let now = Instant::now();
+
+        fail(&breakers, 2, now);
+        breakers.record_success("cd_alpha");
+        fail(&breakers, 2, now);
+        assert!(breakers.allow_at("cd_alpha", now));
+    }
+
+    #[test]
+    fn after_the_open_period_only_one_probe_goes_through() {
+        let breakers = breakers();
+        let now = Instant::now();
+        fail(&breakers, 3, now);
+
+        let later = now + OPEN_FOR;
+        assert!(breakers.allow_at("cd_alpha", later));
+        assert!(!breakers.allow_at("cd_alpha", later));
+        assert!(!breakers.allow_at("cd_alpha", later + PROBE_FOR - Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_circuit() {
+        let breakers = breakers();
+        let now = Instant::now();
+        fail(&breakers, 3, now);
+
+        let later = now + OPEN_FOR;
+        assert!(breakers.allow_at("cd_alpha", later));
+        breakers.record_success("cd_alpha");
+        assert!(breakers.allow_at("cd_alpha", later));
+        assert!(breakers.allow_at("cd_alpha", later));
+    }
+
+    #[test]
+    fn a_failed_probe_reopens_the_circuit_at_once() {
+        let breakers = breakers();
+        let now = Instant::now();
+        fail(&breakers, 3, now);
+
+        let later = now + OPEN_FOR;
+        assert!(breakers.allow_at("cd_alpha", later));
+        breakers.record_failure_at("cd_alpha", later);
+        assert!(!breakers.allow_at("cd_alpha", later + OPEN_FOR - Duration::from_millis(1)));
+        assert!(breakers.allow_at("cd_alpha", later + OPEN_FOR));
+    }
+
+    #[test]
+    fn a_stuck_probe_lets_another_one_go_after_the_call_budget() {
+        let breakers = breakers();
+        let now = Instant::now();
+        fail(&breakers, 3, now);
+
+        // A sonda foi cancelada e nunca registrou sucesso nem falha.
+        let probe_at = now + OPEN_FOR;
+        assert!(breakers.allow_at("cd_alpha", probe_at));
+        assert!(breakers.allow_at("cd_alpha", probe_at + PROBE_FOR));
+        assert!(!breakers.allow_at("cd_alpha", probe_at + PROBE_FOR));
+    }
+}
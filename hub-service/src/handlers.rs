@@ -1,17 +1,127 @@
+use super::catalog::CatalogError;
+use super::search::SearchQuery;
 use super::state::AppState;
-use actix_web::{web, HttpResponse, Responder};
-use common_models::{Product, ProductAvailability, ServiceInfoLookup};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use chrono::Utc;
+use common_models::{
+    total_by_currency, ApiError, AvailabilityResponse, CdStockLevel, CdValuation, ErrorCode,
+    InventorySnapshot, Money, MoneyError, Product, ProductAvailability, ProductPage, ProductPatch,
+    ProductStock, ProductValuation, ServiceInfoLookup, ServiceQuery, ServiceType, SkipReason,
+    StockEventAck, StockEventBatch, StockOverview, UnavailableCd, ValuationQuery, ValuationReport,
+};
 use futures::future::join_all;
+use serde::Deserialize;
+use service_auth::{Caller, Role};
+use service_clients::{CdClient, ClientError};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{error, info, warn, Instrument};
+
+#[derive(Deserialize)]
+pub struct WhoHasQuery {
+    // Inclui CDs com quantidade menor que a pedida, para pedidos divididos entre CDs.
+    #[serde(default)]
+    pub partial: bool,
+}
+
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 500;
+
+#[derive(Deserialize)]
+pub struct ListProductsQuery {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+fn validate_product(product: &Product) -> Result<(), String> {
+    if product.code.trim().is_empty() {
+        return Err("code must not be empty".to_string());
+    }
+    if product.name.trim().is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+    product.price.validate_price()
+}
+
+fn catalog_error_response(product_code: &str, error: CatalogError) -> HttpResponse {
+    let error = match error {
+        CatalogError::AlreadyExists => ApiError::new(
+            ErrorCode::AlreadyExists,
+            format!("Product {} already exists", product_code),
+        ),
+        CatalogError::NotFound => product_not_found(product_code),
+        CatalogError::Io(e) => {
+            error!("Failed to persist catalog: {}", e);
+            ApiError::new(ErrorCode::Internal, "Failed to persist catalog")
+        }
+    };
+    error.error_response()
+}
+
+fn product_not_found(product_code: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        format!("Product {} not found in catalog", product_code),
+    )
+}
 
 pub async fn register_product(
     product: web::Json<Product>,
     data: web::Data<AppState>,
+    caller: Caller,
 ) -> impl Responder {
-    let mut catalog = data.products_catalog.lock().unwrap();
+    if let Err(e) = caller.require(&[Role::Operator]) {
+        return e.error_response();
+    }
+    let product = product.into_inner();
+    if let Err(problem) = validate_product(&product) {
+        return ApiError::new(ErrorCode::BadRequest, problem).error_response();
+    }
+
     let product_code = product.code.clone();
-    catalog.insert(product_code.clone(), product.into_inner());
-    println!("Registered product: {}", product_code);
-    HttpResponse::Ok().body(format!("Product {} registered successfully", product_code))
+    match data
+        .products_catalog
+        .lock()
+        .unwrap()
+        .create(product.clone())
+    {
+        Ok(()) => {
+            info!("Registered product {}", product_code);
+            HttpResponse::Created()
+                .insert_header(("Location", format!("/products/{}", product_code)))
+                .json(product)
+        }
+        Err(e) => catalog_error_response(&product_code, e),
+    }
+}
+
+pub async fn list_products(
+    query: web::Query<ListProductsQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let catalog = data.products_catalog.lock().unwrap();
+    HttpResponse::Ok().json(ProductPage {
+        items: catalog.page(query.offset, limit),
+        total: catalog.len(),
+        offset: query.offset,
+        limit,
+    })
+}
+
+pub async fn search_products(
+    query: web::Query<SearchQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.products_catalog.lock().unwrap().search(&query) {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(problem) => ApiError::new(ErrorCode::BadRequest, problem).error_response(),
+    }
 }
 
 pub async fn get_product_details(
@@ -24,92 +134,496 @@ pub async fn get_product_details(
     if let Some(product) = catalog.get(&product_code) {
         HttpResponse::Ok().json(product)
     } else {
-        HttpResponse::NotFound().body(format!("Product {} not found in catalog", product_code))
+        product_not_found(&product_code).error_response()
+    }
+}
+
+pub async fn replace_product(
+    path: web::Path<String>,
+    product: web::Json<Product>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Operator]) {
+        return e.error_response();
+    }
+    let product_code = path.into_inner();
+    let product = product.into_inner();
+    if product.code != product_code {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            format!(
+                "Product code {} in the body does not match {} in the path",
+                product.code, product_code
+            ),
+        )
+        .error_response();
+    }
+    if let Err(problem) = validate_product(&product) {
+        return ApiError::new(ErrorCode::BadRequest, problem).error_response();
+    }
+
+    match data
+        .products_catalog
+        .lock()
+        .unwrap()
+        .replace(product.clone())
+    {
+        Ok(()) => {
+            info!("Replaced product {}", product_code);
+            HttpResponse::Ok().json(product)
+        }
+        Err(e) => catalog_error_response(&product_code, e),
+    }
+}
+
+pub async fn update_product(
+    path: web::Path<String>,
+    patch: web::Json<ProductPatch>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Operator]) {
+        return e.error_response();
+    }
+    let product_code = path.into_inner();
+    let mut catalog = data.products_catalog.lock().unwrap();
+
+    let product = match catalog.patched(&product_code, &patch) {
+        Ok(product) => product,
+        Err(e) => return catalog_error_response(&product_code, e),
+    };
+    if let Err(problem) = validate_product(&product) {
+        return ApiError::new(ErrorCode::BadRequest, problem).error_response();
+    }
+
+    match catalog.replace(product.clone()) {
+        Ok(()) => {
+            info!("Updated product {}", product_code);
+            HttpResponse::Ok().json(product)
+        }
+        Err(e) => catalog_error_response(&product_code, e),
+    }
+}
+
+pub async fn delete_product(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Operator]) {
+        return e.error_response();
+    }
+    let product_code = path.into_inner();
+    match data.products_catalog.lock().unwrap().delete(&product_code) {
+        Ok(_) => {
+            info!("Deleted product {}", product_code);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => catalog_error_response(&product_code, e),
     }
 }
 
 pub async fn who_has_product(
     path: web::Path<(String, u32)>,
+    query: web::Query<WhoHasQuery>,
     data: web::Data<AppState>,
 ) -> impl Responder {
+    let _timer = data.metrics.who_has_duration.start_timer();
     let (product_code, quantity_needed) = path.into_inner();
-    let client = &data.http_client;
-    let service_discovery_url = &data.service_discovery_url;
+    let partial = query.partial;
+
+    if data.registered_cds.lock().unwrap().is_empty() {
+        if let Err(e) = refresh_registered_cds(&data).await {
+            warn!("Error getting all CD infos from Service Discovery: {}", e);
+            return ApiError::from(e)
+                .context("Failed to query Service Discovery")
+                .error_response();
+        }
+        if data.registered_cds.lock().unwrap().is_empty() {
+            return ApiError::new(
+                ErrorCode::NotFound,
+                "No CDs registered in Service Discovery",
+            )
+            .error_response();
+        }
+    }
+    let skipped_cds = sync_cd_snapshots(&data).await;
 
-    let lookup_all_cds_url = format!("{}/lookup_all", service_discovery_url);
-    let cd_infos_res = client.get(lookup_all_cds_url).send().await;
+    let holders = data.availability.lock().unwrap().holders(&product_code);
+    let catalog_product = data
+        .products_catalog
+        .lock()
+        .unwrap()
+        .get(&product_code)
+        .cloned();
+    let mut available_cds: Vec<ProductAvailability> = holders
+        .into_iter()
+        .filter_map(|(cd_id, product_in_cd, _)| {
+            let quantity = product_in_cd.quantity?;
+            if quantity >= quantity_needed || (partial && quantity > 0) {
+                Some(ProductAvailability {
+                    cd_id,
+                    quantity_available: quantity,
+                    product_info: catalog_product.clone().unwrap_or(product_in_cd),
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+    available_cds.sort_by_key(|cd| std::cmp::Reverse(cd.quantity_available));
 
-    let cd_infos: Vec<ServiceInfoLookup> = match cd_infos_res {
-        Ok(resp) => resp.json().await.unwrap_or_else(|_| {
-            eprintln!("Failed to parse all CD infos from Service Discovery, returning empty Vec.");
-            Vec::new()
+    // Uma lista vazia só é um 404 quando todos os CDs foram consultados.
+    if available_cds.is_empty() && skipped_cds.is_empty() {
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!(
+                "Product {} with quantity {} not found in any CD",
+                product_code, quantity_needed
+            ),
+        )
+        .error_response()
+    } else {
+        HttpResponse::Ok().json(AvailabilityResponse {
+            cds: available_cds,
+            partial: !skipped_cds.is_empty(),
+            skipped_cds,
+        })
+    }
+}
+
+// POST /stock_events: Alterações de estoque publicadas por um CD.
+pub async fn receive_stock_events(
+    batch: web::Json<StockEventBatch>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require_cd(&batch.cd_id) {
+        return e.error_response();
+    }
+    let cd_id = batch.cd_id.clone();
+    match data
+        .availability
+        .lock()
+        .unwrap()
+        .apply_events(batch.into_inner())
+    {
+        Ok(last_seq) => HttpResponse::Ok().json(StockEventAck {
+            last_seq: Some(last_seq),
         }),
-        Err(e) => {
-            eprintln!("Error getting all CD infos from Service Discovery: {}", e);
-            return HttpResponse::InternalServerError().body("Failed to query Service Discovery");
+        Err(last_seq) => {
+            info!(
+                "Stock events from {} do not follow event {:?}; asking it to resync",
+                cd_id, last_seq
+            );
+            ApiError::new(
+                ErrorCode::Conflict,
+                match last_seq {
+                    Some(seq) => format!("Stock events do not follow event {}", seq),
+                    None => format!("No stock snapshot from {}", cd_id),
+                },
+            )
+            .with_details(serde_json::json!({ "last_seq": last_seq }))
+            .error_response()
         }
+    }
+}
+
+// POST /stock_snapshot: Inventário completo de um CD, usado como base para os eventos seguintes.
+pub async fn receive_stock_snapshot(
+    snapshot: web::Json<InventorySnapshot>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require_cd(&snapshot.cd_id) {
+        return e.error_response();
+    }
+    info!(
+        "Stock snapshot from {} at event {}",
+        snapshot.cd_id, snapshot.seq
+    );
+    let last_seq = data
+        .availability
+        .lock()
+        .unwrap()
+        .apply_snapshot(snapshot.into_inner());
+    HttpResponse::Ok().json(StockEventAck {
+        last_seq: Some(last_seq),
+    })
+}
+
+// GET /metrics: Métricas no formato do Prometheus.
+pub async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+    let catalog_products = data.products_catalog.lock().unwrap().len();
+    let registered_cds = data.registered_cds.lock().unwrap().len();
+    data.metrics.catalog_products.set(catalog_products as i64);
+    data.metrics.registered_cds.set(registered_cds as i64);
+    telemetry::metrics_response(&data.metrics.registry)
+}
+
+/// Reloads the list of registered CDs and drops the stock of CDs that left.
+/// Services of other types are left out, so they are never called for stock.
+async fn refresh_registered_cds(data: &AppState) -> Result<(), ClientError> {
+    let cd_infos = data
+        .discovery
+        .lookup_services(&ServiceQuery::of_type(ServiceType::Cd))
+        .await?;
+
+    let registered: HashSet<String> = cd_infos.iter().map(|cd| cd.id.clone()).collect();
+    data.availability.lock().unwrap().retain_cds(&registered);
+    *data.registered_cds.lock().unwrap() = cd_infos;
+    Ok(())
+}
+
+/// Pulls a snapshot from every registered CD the index has no stock for yet
+/// (the hub just started, or the CD has not published). Returns the CDs that
+/// could not be synced.
+async fn sync_cd_snapshots(data: &AppState) -> Vec<UnavailableCd> {
+    let missing: Vec<ServiceInfoLookup> = {
+        let availability = data.availability.lock().unwrap();
+        data.registered_cds
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|cd| !availability.has_baseline(&cd.id))
+            .cloned()
+            .collect()
     };
 
-    if cd_infos.is_empty() {
-        return HttpResponse::NotFound().body("No CDs registered in Service Discovery.");
+    let futures = missing.into_iter().map(|cd_info| async move {
+        let cd_id = cd_info.id.clone();
+        (cd_id, fetch_snapshot_from_cd(data, &cd_info).await)
+    });
+
+    let mut unavailable = Vec::new();
+    for (cd_id, result) in join_all(futures).await {
+        match result {
+            Ok(snapshot) => {
+                data.availability.lock().unwrap().apply_snapshot(snapshot);
+            }
+            Err((reason, error)) => {
+                warn!(reason = ?reason, "Skipped stock snapshot of CD {}: {}", cd_id, error);
+                data.metrics.record_fanout_error(&cd_id, reason);
+                unavailable.push(UnavailableCd {
+                    cd_id,
+                    reason,
+                    error,
+                });
+            }
+        }
     }
+    unavailable
+}
 
-    let mut futures = Vec::new();
+/// Fetches a CD's stock snapshot within the configured timeout, retrying
+/// failed attempts with a short backoff. Calls to a CD whose circuit is open
+/// are skipped without touching the network.
+async fn fetch_snapshot_from_cd(
+    data: &AppState,
+    cd_info: &ServiceInfoLookup,
+) -> Result<InventorySnapshot, (SkipReason, String)> {
+    if !data.breakers.allow(&cd_info.id) {
+        return Err((
+            SkipReason::CircuitOpen,
+            "circuit open after repeated failures".to_string(),
+        ));
+    }
 
-    for cd_info in cd_infos {
-        let client = client.clone();
-        let product_code = product_code.clone();
-        let catalog_data = data.products_catalog.clone();
+    let cd = CdClient::for_service(data.http_client.clone(), cd_info)
+        .map_err(|e| (SkipReason::Failed, e.to_string()))?
+        .with_timeout(data.fanout.request_timeout);
+    let mut last_failure = (SkipReason::Failed, String::new());
+    for attempt in 0..=data.fanout.retries {
+        if attempt > 0 {
+            sleep(Duration::from_millis(100 * 2u64.pow(attempt - 1))).await;
+        }
+        last_failure = match cd.stock_snapshot().await {
+            Ok(snapshot) => {
+                data.breakers.record_success(&cd_info.id);
+                return Ok(snapshot);
+            }
+            Err(e) if e.is_timeout() => (
+                SkipReason::TimedOut,
+                format!("no answer within {:?}", data.fanout.request_timeout),
+            ),
+            Err(e) => (SkipReason::Failed, e.to_string()),
+        };
+    }
 
-        futures.push(async move {
-            let cd_url = format!(
-                "http://{}:{}/inventory/{}",
-                cd_info.ip, cd_info.port, product_code
-            );
-            let product_res = client.get(&cd_url).send().await;
-
-            match product_res {
-                Ok(resp) => {
-                    if resp.status().is_success() {
-                        let product_in_cd: Product = resp.json().await.ok()?;
-                        if let Some(quantity) = product_in_cd.quantity {
-                            if quantity >= quantity_needed {
-                                let product_details = catalog_data
-                                    .lock()
-                                    .unwrap()
-                                    .get(&product_code)
-                                    .cloned()
-                                    .unwrap_or_else(|| product_in_cd.clone());
-                                return Some(ProductAvailability {
-                                    cd_id: cd_info.id,
-                                    quantity_available: quantity,
-                                    product_info: product_details,
-                                });
-                            }
-                        }
-                    }
-                    None
-                }
-                Err(e) => {
-                    eprintln!(
-                        "Error querying CD {} for product {}: {}",
-                        cd_info.id, product_code, e
-                    );
-                    None
+    data.breakers.record_failure(&cd_info.id);
+    Err(last_failure)
+}
+
+/// Keeps the availability index in line with Service Discovery: forgets CDs
+/// that left and pulls snapshots for CDs it has no stock for.
+pub async fn reconcile_availability(state: web::Data<AppState>) {
+    loop {
+        async {
+            match refresh_registered_cds(&state).await {
+                Ok(()) => {
+                    sync_cd_snapshots(&state).await;
                 }
+                Err(e) => warn!("Error getting all CD infos from Service Discovery: {}", e),
+            }
+        }
+        .instrument(tracing::info_span!("reconcile_availability"))
+        .await;
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Sums the stock of every product (or only `only_code`) across the CDs.
+/// Catalog products that no CD carries are listed with zero stock.
+async fn stock_overview(data: &AppState, only_code: Option<&str>) -> StockOverview {
+    let unavailable_cds = sync_cd_snapshots(data).await;
+
+    let mut products: BTreeMap<String, ProductStock> = BTreeMap::new();
+    {
+        let catalog = data.products_catalog.lock().unwrap();
+        let catalog_products = match only_code {
+            Some(code) => catalog.get(code).cloned().into_iter().collect(),
+            None => catalog.page(0, catalog.len()),
+        };
+        for product in catalog_products {
+            products.insert(
+                product.code.clone(),
+                ProductStock {
+                    product_code: product.code,
+                    name: product.name,
+                    total_quantity: 0,
+                    cds: Vec::new(),
+                },
+            );
+        }
+    }
+
+    let availability = data.availability.lock().unwrap();
+    let codes: Vec<String> = match only_code {
+        Some(code) => vec![code.to_string()],
+        None => availability.product_codes().cloned().collect(),
+    };
+    for code in codes {
+        for (cd_id, product, as_of) in availability.holders(&code) {
+            let quantity = product.quantity.unwrap_or(0);
+            let entry = products
+                .entry(code.clone())
+                .or_insert_with(|| ProductStock {
+                    product_code: code.clone(),
+                    name: product.name.clone(),
+                    total_quantity: 0,
+                    cds: Vec::new(),
+                });
+            entry.total_quantity += u64::from(quantity);
+            entry.cds.push(CdStockLevel {
+                cd_id,
+                quantity,
+                as_of,
+            });
+        }
+    }
+    for product in products.values_mut() {
+        product.cds.sort_by(|a, b| a.cd_id.cmp(&b.cd_id));
+    }
+
+    StockOverview {
+        generated_at: Utc::now(),
+        products: products.into_values().collect(),
+        unavailable_cds,
+    }
+}
+
+/// Values the stock of every CD at the catalog price, or at the price the CD
+/// reports for products missing from the catalog.
+async fn valuation_report(
+    data: &AppState,
+    query: &ValuationQuery,
+) -> Result<ValuationReport, MoneyError> {
+    let unavailable_cds = sync_cd_snapshots(data).await;
+
+    let mut products = Vec::new();
+    let mut cds: BTreeMap<String, (u64, Vec<Money>)> = BTreeMap::new();
+    {
+        let catalog = data.products_catalog.lock().unwrap();
+        let availability = data.availability.lock().unwrap();
+        for code in availability.product_codes() {
+            let holders = availability.holders(code);
+            let Some((_, first, _)) = holders.first() else {
+                continue;
+            };
+            let (name, unit_price) = match catalog.get(code) {
+                Some(product) => (product.name.clone(), product.price),
+                None => (first.name.clone(), first.price),
+            };
+            let mut total_quantity = 0;
+            for (cd_id, product, _) in &holders {
+                let quantity = u64::from(product.quantity.unwrap_or(0));
+                total_quantity += quantity;
+                let cd = cds.entry(cd_id.clone()).or_default();
+                cd.0 += quantity;
+                cd.1.push(unit_price.times(quantity)?);
             }
-        });
+            products.push(ProductValuation::new(
+                code.clone(),
+                name,
+                total_quantity,
+                unit_price,
+            )?);
+        }
     }
 
-    let results: Vec<Option<ProductAvailability>> = join_all(futures).await;
-    let available_cds: Vec<ProductAvailability> = results.into_iter().flatten().collect();
+    let mut report = ValuationReport::new(None, products, query)?;
+    report.cds = cds
+        .into_iter()
+        .map(|(cd_id, (total_quantity, values))| {
+            Ok(CdValuation {
+                cd_id,
+                total_quantity,
+                total_value: total_by_currency(values)?,
+            })
+        })
+        .collect::<Result<_, MoneyError>>()?;
+    report.unavailable_cds = unavailable_cds;
+    Ok(report)
+}
 
-    if available_cds.is_empty() {
-        HttpResponse::NotFound().body(format!(
-            "Product {} with quantity {} not found in any CD",
-            product_code, quantity_needed
-        ))
+// GET /reports/valuation: Valor do estoque de toda a rede, por produto e por CD, em JSON ou CSV.
+pub async fn get_valuation_report(
+    query: web::Query<ValuationQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match valuation_report(&data, &query).await {
+        Ok(report) => report.respond(query.format),
+        Err(e) => ApiError::new(
+            ErrorCode::Internal,
+            format!("Cannot value the stock: {}", e),
+        )
+        .error_response(),
+    }
+}
+
+// GET /stock: Estoque total de cada produto somando todos os CDs, com o detalhe por CD.
+pub async fn get_stock_overview(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(stock_overview(&data, None).await)
+}
+
+// GET /stock/{product_code}: O mesmo que /stock, só para um produto.
+pub async fn get_product_stock(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let product_code = path.into_inner();
+    let overview = stock_overview(&data, Some(&product_code)).await;
+    if overview.products.is_empty() && overview.unavailable_cds.is_empty() {
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!(
+                "Product {} is not in the catalog nor in any CD",
+                product_code
+            ),
+        )
+        .error_response()
     } else {
-        HttpResponse::Ok().json(available_cds)
+        HttpResponse::Ok().json(overview)
     }
 }
@@ -1,36 +1,126 @@
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
-use reqwest::Client;
-use std::collections::HashMap;
+use service_clients::DiscoveryClient;
+use std::env;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use tracing::{error, info};
 
+mod availability;
+mod catalog;
+mod fanout;
 mod handlers;
+mod metrics;
+mod search;
 mod state;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let service_discovery_url = "http://127.0.0.1:8080".to_string();
-    let ip = "127.0.0.1";
-    let port = 8082;
+    let _telemetry = telemetry::init("hub-service", "hub");
+    if let Err(e) = service_auth::init("hub", service_auth::Role::Hub) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let tls = match service_auth::Tls::from_env() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("Invalid TLS setup: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Lista separada por vírgulas das réplicas do Service Discovery.
+    let service_discovery_urls: Vec<String> = env::var("DISCOVERY_URLS")
+        .unwrap_or_else(|_| "http://127.0.0.1:8080".to_string())
+        .split(',')
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .collect();
+    let fanout = match fanout::FanoutConfig::from_env() {
+        Ok(fanout) => fanout,
+        Err(problems) => {
+            error!("Invalid hub configuration:");
+            for problem in problems {
+                error!("  - {}", problem);
+            }
+            std::process::exit(1);
+        }
+    };
+    let bind_address = env::var("HUB_BIND").unwrap_or_else(|_| "127.0.0.1:8082".to_string());
+
+    let data_dir = env::var("HUB_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("data/hub"));
+    let catalog = match catalog::CatalogStore::open(&data_dir) {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            error!("Failed to open catalog in {}: {}", data_dir.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let http_client = service_clients::http_client(fanout.request_timeout, tls.as_ref());
+    let discovery = match DiscoveryClient::new(http_client.clone(), &service_discovery_urls) {
+        Ok(discovery) => discovery,
+        Err(e) => {
+            error!("Invalid DISCOVERY_URLS: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let app_state = web::Data::new(state::AppState {
-        products_catalog: Arc::new(Mutex::new(HashMap::new())),
-        service_discovery_url: service_discovery_url.clone(),
-        http_client: Client::new(),
+        products_catalog: Arc::new(Mutex::new(catalog)),
+        availability: Arc::new(Mutex::new(availability::AvailabilityIndex::default())),
+        registered_cds: Arc::new(Mutex::new(Vec::new())),
+        discovery,
+        http_client,
+        breakers: fanout::CircuitBreakers::new(&fanout),
+        fanout,
+        metrics: metrics::Metrics::new(),
     });
 
-    println!("Hub Service running on http://{}:{}", ip, port);
+    tokio::spawn(handlers::reconcile_availability(app_state.clone()));
 
-    HttpServer::new(move || {
+    info!(
+        "Hub Service running on {}://{}",
+        service_auth::scheme(tls.as_ref()),
+        bind_address
+    );
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .service(web::resource("/products").post(handlers::register_product))
-            .service(web::resource("/products/{product_code}").get(handlers::get_product_details))
+            .wrap(from_fn(service_auth::authenticate))
+            .wrap(from_fn(telemetry::trace_id_header))
+            .wrap(telemetry::TracingLogger::default())
+            .configure(common_models::configure_error_responses)
+            .service(
+                web::resource("/products")
+                    .get(handlers::list_products)
+                    .post(handlers::register_product),
+            )
+            .service(web::resource("/products/search").get(handlers::search_products))
+            .service(
+                web::resource("/products/{product_code}")
+                    .get(handlers::get_product_details)
+                    .put(handlers::replace_product)
+                    .patch(handlers::update_product)
+                    .delete(handlers::delete_product),
+            )
+            .service(web::resource("/stock").get(handlers::get_stock_overview))
+            .service(web::resource("/stock_events").post(handlers::receive_stock_events))
+            .service(web::resource("/stock_snapshot").post(handlers::receive_stock_snapshot))
+            .service(web::resource("/stock/{product_code}").get(handlers::get_product_stock))
+            .service(web::resource("/reports/valuation").get(handlers::get_valuation_report))
             .service(
                 web::resource("/who_has_product/{product_code}/{quantity_needed}")
                     .get(handlers::who_has_product),
             )
-    })
-    .bind(format!("{}:{}", ip, port))?
-    .run()
-    .await
+            .service(web::resource("/metrics").get(handlers::get_metrics))
+    });
+    let server = match &tls {
+        Some(tls) => server.bind_rustls_0_23(&bind_address, tls.server_config())?,
+        None => server.bind(&bind_address)?,
+    };
+    server.run().await
 }
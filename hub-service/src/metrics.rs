@@ -0,0 +1,60 @@
+use common_models::SkipReason;
+use prometheus::{Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+
+/// Prometheus metrics of the hub, served on `/metrics`.
+pub struct Metrics {
+    pub registry: Registry,
+    pub who_has_duration: Histogram,
+    /// Failed calls to a CD by CD and `SkipReason`, circuit-open skips included.
+    pub fanout_errors: IntCounterVec,
+    /// Set from the catalog on every scrape.
+    pub catalog_products: IntGauge,
+    /// Set from the last Service Discovery lookup on every scrape.
+    pub registered_cds: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let who_has_duration = Histogram::with_opts(HistogramOpts::new(
+            "hub_who_has_duration_seconds",
+            "Time to answer who_has_product",
+        ))
+        .unwrap();
+        let fanout_errors = IntCounterVec::new(
+            Opts::new("hub_fanout_errors_total", "Failed calls to CDs"),
+            &["cd_id", "reason"],
+        )
+        .unwrap();
+        let catalog_products =
+            IntGauge::new("hub_catalog_products", "Products in the catalog").unwrap();
+        let registered_cds =
+            IntGauge::new("hub_registered_cds", "CDs registered in Service Discovery").unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(who_has_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(fanout_errors.clone())).unwrap();
+        registry
+            .register(Box::new(catalog_products.clone()))
+            .unwrap();
+        registry.register(Box::new(registered_cds.clone())).unwrap();
+
+        Metrics {
+            registry,
+            who_has_duration,
+            fanout_errors,
+            catalog_products,
+            registered_cds,
+        }
+    }
+
+    pub fn record_fanout_error(&self, cd_id: &str, reason: SkipReason) {
+        let reason = match reason {
+            SkipReason::CircuitOpen => "circuit_open",
+            SkipReason::TimedOut => "timed_out",
+            SkipReason::Failed => "failed",
+        };
+        self.fanout_errors.with_label_values(&[cd_id, reason]).inc();
+    }
+}
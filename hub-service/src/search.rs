@@ -0,0 +1,576 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use common_models::{Currency, Decimal, Product, ProductSearchPage};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    Relevance,
+    Code,
+    Name,
+    Price,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+    /// Currency of `min_price` and `max_price`; products priced in another
+    /// currency never match a price filter.
+    pub currency: Option<Currency>,
+    pub sort: Option<SortField>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SortKey {
+    Score(u32),
+    Text(String),
+    /// Prices sort by currency first, so amounts in different currencies
+    /// are never compared.
+    Price(Currency, Decimal),
+}
+
+impl SortKey {
+    fn cmp(&self, other: &SortKey) -> Ordering {
+        match (self, other) {
+            (SortKey::Score(a), SortKey::Score(b)) => a.cmp(b),
+            (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+            (SortKey::Price(a, x), SortKey::Price(b, y)) => (a, x).cmp(&(b, y)),
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+/// Position after the last item of a page. It carries the sort it was made
+/// for, so it cannot be replayed against a different ordering.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    sort: SortField,
+    order: SortOrder,
+    key: SortKey,
+    code: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Option<Cursor> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+fn price_key(product: &Product) -> (Currency, Decimal) {
+    (product.price.currency(), product.price.amount())
+}
+
+/// Lowercases `text` and strips accents, so "Garrafas de Água" and
+/// "garrafas de agua" compare equal.
+pub fn normalize(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    normalize(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Every suffix of `term`, the term itself included. A query term found
+/// inside a word is a prefix of one of its suffixes.
+fn suffixes(term: &str) -> impl Iterator<Item = &str> {
+    term.char_indices().map(move |(i, _)| &term[i..])
+}
+
+/// Inverted index over product names and codes plus an ordered price index
+/// per currency.
+/// `CatalogStore` updates it on every change to the catalog.
+#[derive(Default)]
+pub struct SearchIndex {
+    terms: BTreeMap<String, BTreeSet<String>>,
+    /// Suffixes of the indexed terms, each with the terms it ends. Searching
+    /// a prefix range here finds whole words, word prefixes and substrings
+    /// without going through every term.
+    suffixes: BTreeMap<String, BTreeSet<String>>,
+    prices: BTreeMap<(Currency, Decimal), BTreeSet<String>>,
+}
+
+impl SearchIndex {
+    pub fn insert(&mut self, product: &Product) {
+        for term in Self::terms_of(product) {
+            if !self.terms.contains_key(&term) {
+                for suffix in suffixes(&term) {
+                    self.suffixes
+                        .entry(suffix.to_string())
+                        .or_default()
+                        .insert(term.clone());
+                }
+            }
+            self.terms
+                .entry(term)
+                .or_default()
+                .insert(product.code.clone());
+        }
+        self.prices
+            .entry(price_key(product))
+            .or_default()
+            .insert(product.code.clone());
+    }
+
+    pub fn remove(&mut self, product: &Product) {
+        for term in Self::terms_of(product) {
+            if let Some(codes) = self.terms.get_mut(&term) {
+                codes.remove(&product.code);
+                if codes.is_empty() {
+                    self.terms.remove(&term);
+                    self.forget_suffixes(&term);
+                }
+            }
+        }
+        let key = price_key(product);
+        if let Some(codes) = self.prices.get_mut(&key) {
+            codes.remove(&product.code);
+            if codes.is_empty() {
+                self.prices.remove(&key);
+            }
+        }
+    }
+
+    pub fn search(
+        &self,
+        products: &BTreeMap<String, Product>,
+        query: &SearchQuery,
+    ) -> Result<ProductSearchPage, String> {
+        let currency = query.currency.unwrap_or_default();
+        let min_price = query.min_price.unwrap_or(Decimal::ZERO).max(Decimal::ZERO);
+        let max_price = query.max_price.unwrap_or(Decimal::MAX);
+        if min_price > max_price {
+            return Err("min_price must not be greater than max_price".to_string());
+        }
+
+        let query_terms = query.q.as_deref().map(tokenize).unwrap_or_default();
+        let sort = query.sort.unwrap_or(if query_terms.is_empty() {
+            SortField::Code
+        } else {
+            SortField::Relevance
+        });
+        let order = query.order.unwrap_or(if sort == SortField::Relevance {
+            SortOrder::Desc
+        } else {
+            SortOrder::Asc
+        });
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .clamp(1, MAX_SEARCH_LIMIT);
+
+        let cursor = match &query.cursor {
+            Some(raw) => match Cursor::decode(raw) {
+                Some(cursor) if cursor.sort == sort && cursor.order == order => Some(cursor),
+                Some(_) => return Err("cursor was issued for a different sort".to_string()),
+                None => return Err("invalid cursor".to_string()),
+            },
+            None => None,
+        };
+
+        let mut matches = self.match_terms(&query_terms);
+        if query.min_price.is_some() || query.max_price.is_some() {
+            let in_range: BTreeSet<&String> = self
+                .prices
+                .range((currency, min_price)..=(currency, max_price))
+                .flat_map(|(_, codes)| codes)
+                .collect();
+            matches = match matches {
+                Some(scores) => Some(
+                    scores
+                        .into_iter()
+                        .filter(|(code, _)| in_range.contains(code))
+                        .collect(),
+                ),
+                None => Some(in_range.into_iter().map(|code| (code.clone(), 0)).collect()),
+            };
+        }
+        let matches: HashMap<String, u32> = match matches {
+            Some(scores) => scores,
+            None => products.keys().map(|code| (code.clone(), 0)).collect(),
+        };
+
+        let mut rows: Vec<(SortKey, &Product)> = matches
+            .iter()
+            .filter_map(|(code, score)| products.get(code).map(|product| (score, product)))
+            .map(|(score, product)| {
+                let key = match sort {
+                    SortField::Relevance => SortKey::Score(*score),
+                    SortField::Code => SortKey::Text(product.code.clone()),
+                    SortField::Name => SortKey::Text(normalize(&product.name)),
+                    SortField::Price => {
+                        SortKey::Price(product.price.currency(), product.price.amount())
+                    }
+                };
+                (key, product)
+            })
+            .collect();
+
+        // Ordem total: a chave na direção pedida e, no empate, o código em
+        // ordem crescente. O cursor é uma posição nessa ordem.
+        let compare = |key: &SortKey, code: &str, other_key: &SortKey, other_code: &str| {
+            let primary = key.cmp(other_key);
+            let primary = match order {
+                SortOrder::Asc => primary,
+                SortOrder::Desc => primary.reverse(),
+            };
+            primary.then_with(|| code.cmp(other_code))
+        };
+        rows.sort_by(|a, b| compare(&a.0, &a.1.code, &b.0, &b.1.code));
+
+        let start = match &cursor {
+            Some(cursor) => rows.partition_point(|(key, product)| {
+                compare(key, &product.code, &cursor.key, &cursor.code) != Ordering::Greater
+            }),
+            None => 0,
+        };
+        let page: Vec<&(SortKey, &Product)> = rows.iter().skip(start).take(limit).collect();
+        let next_cursor = match page.last() {
+            Some((key, product)) if start + page.len() < rows.len() => Some(
+                Cursor {
+                    sort,
+                    order,
+                    key: key.clone(),
+                    code: product.code.clone(),
+                }
+                .encode(),
+            ),
+            _ => None,
+        };
+
+        Ok(ProductSearchPage {
+            items: page.iter().map(|(_, product)| (*product).clone()).collect(),
+            total_matches: rows.len(),
+            next_cursor,
+        })
+    }
+
+    /// Codes matching every query term, with a relevance score: a whole word
+    /// counts 3, a word prefix 2 and a substring 1. `None` means no text filter.
+    fn match_terms(&self, query_terms: &[String]) -> Option<HashMap<String, u32>> {
+        if query_terms.is_empty() {
+            return None;
+        }
+
+        let mut total: Option<HashMap<String, u32>> = None;
+        for query_term in query_terms {
+            let mut best: HashMap<String, u32> = HashMap::new();
+            let containing = self
+                .suffixes
+                .range::<str, _>((Bound::Included(query_term.as_str()), Bound::Unbounded))
+                .take_while(|(suffix, _)| suffix.starts_with(query_term.as_str()))
+                .flat_map(|(_, terms)| terms);
+            for term in containing {
+                let score = if term == query_term {
+                    3
+                } else if term.starts_with(query_term.as_str()) {
+                    2
+                } else {
+                    1
+                };
+                for code in &self.terms[term] {
+                    let entry = best.entry(code.clone()).or_insert(0);
+                    *entry = (*entry).max(score);
+                }
+            }
+
+            total = Some(match total {
+                None => best,
+                Some(previous) => previous
+                    .into_iter()
+                    .filter_map(|(code, score)| best.get(&code).map(|s| (code, score + s)))
+                    .collect(),
+            });
+        }
+        total
+    }
+
+    fn forget_suffixes(&mut self, term: &str) {
+        for suffix in suffixes(term) {
+            if let Some(terms) = self.suffixes.get_mut(suffix) {
+                terms.remove(term);
+                if terms.is_empty() {
+                    self.suffixes.remove(suffix);
+                }
+            }
+        }
+    }
+
+    fn terms_of(product: &Product) -> BTreeSet<String> {
+        tokenize(&product.name)
+            .into_iter()
+            .chain(tokenize(&product.code))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_models::Money;
+
+    struct Catalog {
+        products: BTreeMap<String, Product>,
+        index: SearchIndex,
+    }
+
+    impl Catalog {
+        fn new(products: &[(&str, &str, &str)]) -> Self {
+            let mut catalog = Catalog {
+                products: BTreeMap::new(),
+                index: SearchIndex::default(),
+            };
+            for (code, name, price) in products {
+                catalog.insert(code, name, price, Currency::BRL);
+            }
+            catalog
+        }
+
+        fn insert(&mut self, code: &str, name: &str, price: &str, currency: Currency) {
+            let product = Product {
+                code: code.to_string(),
+                name: name.to_string(),
+                price: Money::new(price.parse().unwrap(), currency),
+                quantity: None,
+            };
+            self.index.insert(&product);
+            self.products.insert(product.code.clone(), product);
+        }
+
+        fn remove(&mut self, code: &str) {
+            let product = self.products.remove(code).unwrap();
+            self.index.remove(&product);
+        }
+
+        fn search(&self, query: SearchQuery) -> ProductSearchPage {
+            self.index.search(&self.products, &query).unwrap()
+        }
+
+        fn codes(&self, query: SearchQuery) -> Vec<String> {
+            codes(&self.search(query))
+        }
+    }
+
+    fn codes(page: &ProductSearchPage) -> Vec<String> {
+        page.items.iter().map(|p| p.code.clone()).collect()
+    }
+
+    fn query() -> SearchQuery {
+        SearchQuery {
+            q: None,
+            min_price: None,
+            max_price: None,
+            currency: None,
+            sort: None,
+            order: None,
+            limit: None,
+            cursor: None,
+        }
+    }
+
+    fn text(q: &str) -> SearchQuery {
+        SearchQuery {
+            q: Some(q.to_string()),
+            ..query()
+        }
+    }
+
+    fn sample() -> Catalog {
+        Catalog::new(&[
+            ("garrafas", "Garrafas de Água", "12.50"),
+            ("aguardente", "Aguardente Mineira", "45.00"),
+            ("celulares", "Celulares", "1200.00"),
+            ("capas", "Capas para Celular", "30.00"),
+            ("guarda_chuvas", "Guarda-chuvas", "60.00"),
+        ])
+    }
+
+    #[test]
+    fn matching_ignores_case_and_accents() {
+        let catalog = sample();
+        assert_eq!(catalog.codes(text("AGUA")), vec!["garrafas", "aguardente"]);
+        assert_eq!(catalog.codes(text("Água")), vec!["garrafas", "aguardente"]);
+        assert_eq!(catalog.codes(text("mineira")), vec!["aguardente"]);
+    }
+
+    #[test]
+    fn whole_words_rank_above_prefixes_and_substrings() {
+        let catalog = sample();
+        // "celular" é palavra inteira em capas, início de "celulares".
+        assert_eq!(catalog.codes(text("celular")), vec!["capas", "celulares"]);
+        // "uard" só aparece como trecho.
+        assert_eq!(
+            catalog.codes(text("uard")),
+            vec!["aguardente", "guarda_chuvas"]
+        );
+    }
+
+    #[test]
+    fn every_term_must_match() {
+        let catalog = sample();
+        assert_eq!(catalog.codes(text("capas celular")), vec!["capas"]);
+        assert!(catalog.codes(text("capas garrafa")).is_empty());
+    }
+
+    #[test]
+    fn removed_products_no_longer_match() {
+        let mut catalog = sample();
+        catalog.remove("aguardente");
+        assert_eq!(catalog.codes(text("agua")), vec!["garrafas"]);
+        assert!(catalog.codes(text("mineira")).is_empty());
+        assert!(catalog
+            .index
+            .suffixes
+            .keys()
+            .all(|s| !s.contains("mineira")));
+    }
+
+    #[test]
+    fn price_filters_are_inclusive_and_per_currency() {
+        let mut catalog = sample();
+        let usd: Currency = "USD".parse().unwrap();
+        catalog.insert("usb", "Cabo USB", "30.00", usd);
+
+        let in_range = SearchQuery {
+            min_price: Some("30".parse().unwrap()),
+            max_price: Some("60".parse().unwrap()),
+            ..query()
+        };
+        assert_eq!(
+            catalog.codes(in_range),
+            vec!["aguardente", "capas", "guarda_chuvas"]
+        );
+
+        let cheap = SearchQuery {
+            max_price: Some("30".parse().unwrap()),
+            ..query()
+        };
+        assert_eq!(catalog.codes(cheap), vec!["capas", "garrafas"]);
+
+        let dollars = SearchQuery {
+            min_price: Some("1".parse().unwrap()),
+            currency: Some(usd),
+            ..query()
+        };
+        assert_eq!(catalog.codes(dollars), vec!["usb"]);
+
+        let with_text = SearchQuery {
+            min_price: Some("20".parse().unwrap()),
+            ..text("agua")
+        };
+        assert_eq!(catalog.codes(with_text), vec!["aguardente"]);
+    }
+
+    #[test]
+    fn min_price_above_max_price_is_rejected() {
+        let catalog = sample();
+        let query = SearchQuery {
+            min_price: Some("10".parse().unwrap()),
+            max_price: Some("5".parse().unwrap()),
+            ..query()
+        };
+        assert!(catalog.index.search(&catalog.products, &query).is_err());
+    }
+
+    #[test]
+    fn pages_follow_each_other_without_repeats_or_gaps() {
+        let mut catalog = Catalog::new(&[]);
+        for i in 0..7 {
+            // Preços repetidos: o desempate é pelo código.
+            catalog.insert(
+                &format!("p{}", i),
+                "Produto",
+                &format!("{}", i % 3),
+                Currency::BRL,
+            );
+        }
+        let by_price = |cursor: Option<String>| SearchQuery {
+            sort: Some(SortField::Price),
+            limit: Some(3),
+            cursor,
+            ..query()
+        };
+
+        let first = catalog.search(by_price(None));
+        assert_eq!(codes(&first), vec!["p0", "p3", "p6"]);
+        assert_eq!(first.total_matches, 7);
+
+        // Um produto novo antes do cursor não faz itens se repetirem.
+        catalog.insert("p00", "Produto", "0", Currency::BRL);
+        let second = catalog.search(by_price(first.next_cursor.clone()));
+        assert_eq!(codes(&second), vec!["p1", "p4", "p2"]);
+
+        let third = catalog.search(by_price(second.next_cursor.clone()));
+        assert_eq!(codes(&third), vec!["p5"]);
+        assert!(third.next_cursor.is_none());
+    }
+
+    #[test]
+    fn cursor_from_another_sort_is_rejected() {
+        let catalog = sample();
+        let first = catalog.search(SearchQuery {
+            limit: Some(1),
+            ..query()
+        });
+        let by_name = SearchQuery {
+            sort: Some(SortField::Name),
+            cursor: first.next_cursor,
+            ..query()
+        };
+        assert!(catalog.index.search(&catalog.products, &by_name).is_err());
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor {
+            sort: SortField::Price,
+            order: SortOrder::Desc,
+            key: SortKey::Price(Currency::BRL, "1200.50".parse().unwrap()),
+            code: "celulares".to_string(),
+        };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.sort, cursor.sort);
+        assert_eq!(decoded.order, cursor.order);
+        assert_eq!(decoded.key, cursor.key);
+        assert_eq!(decoded.code, cursor.code);
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        assert!(Cursor::decode("not a cursor").is_none());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode(b"{}")).is_none());
+    }
+}
@@ -1,10 +1,21 @@
-use common_models::Product;
+use super::availability::AvailabilityIndex;
+use super::catalog::CatalogStore;
+use super::fanout::{CircuitBreakers, FanoutConfig};
+use super::metrics::Metrics;
+use common_models::ServiceInfoLookup;
 use reqwest::Client;
-use std::collections::HashMap;
+use service_clients::DiscoveryClient;
 use std::sync::{Arc, Mutex};
 
 pub struct AppState {
-    pub products_catalog: Arc<Mutex<HashMap<String, Product>>>,
-    pub service_discovery_url: String,
+    pub products_catalog: Arc<Mutex<CatalogStore>>,
+    pub availability: Arc<Mutex<AvailabilityIndex>>,
+    /// CDs registered in Service Discovery, refreshed by `reconcile_availability`.
+    pub registered_cds: Arc<Mutex<Vec<ServiceInfoLookup>>>,
+    pub discovery: DiscoveryClient,
+    /// Shared by the `CdClient`s built for each CD.
     pub http_client: Client,
+    pub fanout: FanoutConfig,
+    pub breakers: CircuitBreakers,
+    pub metrics: Metrics,
 }
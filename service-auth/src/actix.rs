@@ -0,0 +1,114 @@
+use super::{key, Claims, Role};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, ResponseError};
+use common_models::{ApiError, ErrorCode};
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+
+/// Middleware checking the bearer token of every request. A valid token
+/// makes its `Claims` available to the handlers through `Caller`; an invalid
+/// one is answered with 401. Requests without a token go through anonymous,
+/// so public endpoints keep working.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    match verify(&req) {
+        Ok(Some(claims)) => {
+            req.extensions_mut().insert(claims);
+        }
+        Ok(None) => {}
+        Err(e) => return Ok(req.into_response(e.error_response()).map_into_right_body()),
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+fn verify(req: &ServiceRequest) -> Result<Option<Claims>, ApiError> {
+    let (Some(key), Some(header)) = (key(), req.headers().get(AUTHORIZATION)) else {
+        return Ok(None);
+    };
+    let token = header
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| unauthorized("Authorization must be a Bearer token"))?;
+    key.verify(token.trim())
+        .map(Some)
+        .map_err(|e| unauthorized(&e.to_string()))
+}
+
+fn unauthorized(message: &str) -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, message)
+}
+
+/// Identity of whoever sent the request, as checked by `authenticate`.
+pub struct Caller {
+    claims: Option<Claims>,
+}
+
+impl Caller {
+    pub fn claims(&self) -> Option<&Claims> {
+        self.claims.as_ref()
+    }
+
+    /// Accepts callers with one of `roles`. Everyone is accepted when
+    /// authentication is off.
+    pub fn require(&self, roles: &[Role]) -> Result<(), ApiError> {
+        if key().is_none() {
+            return Ok(());
+        }
+        let claims = self
+            .claims
+            .as_ref()
+            .ok_or_else(|| unauthorized("This operation requires authentication"))?;
+        if roles.contains(&claims.role) {
+            Ok(())
+        } else {
+            Err(ApiError::new(
+                ErrorCode::Forbidden,
+                format!("{} is not allowed to do this", claims.sub),
+            ))
+        }
+    }
+
+    /// Accepts only CD `cd_id` itself, so one CD cannot act in another's name.
+    pub fn require_cd(&self, cd_id: &str) -> Result<(), ApiError> {
+        self.require(&[Role::Cd])?;
+        self.require_sub(cd_id)
+    }
+
+    /// Accepts the service `id` itself, whatever its role, and operators.
+    pub fn require_self_or_operator(&self, id: &str) -> Result<(), ApiError> {
+        self.require(&[Role::Cd, Role::Hub, Role::Discovery, Role::Operator])?;
+        match &self.claims {
+            Some(claims) if claims.role == Role::Operator => Ok(()),
+            _ => self.require_sub(id),
+        }
+    }
+
+    fn require_sub(&self, id: &str) -> Result<(), ApiError> {
+        match &self.claims {
+            Some(claims) if claims.sub != id => Err(ApiError::new(
+                ErrorCode::Forbidden,
+                format!("{} cannot act as {}", claims.sub, id),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Caller {
+            claims: req.extensions().get::<Claims>().cloned(),
+        }))
+    }
+}
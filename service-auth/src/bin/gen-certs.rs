@@ -0,0 +1,71 @@
+//! Generates a local CA and one certificate per service, for TLS and mutual
+//! TLS between the services:
+//!
+//! `gen-certs <dir> <name>...`, e.g. `gen-certs certs discovery hub cd_alpha`.
+//!
+//! Writes `ca.pem` and, for each name, `<name>.pem` and `<name>-key.pem`. The
+//! certificates are valid for `localhost`, `127.0.0.1` and the name itself,
+//! both as server and as client certificates.
+
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((dir, names)) = args.split_first().filter(|(_, names)| !names.is_empty()) else {
+        eprintln!("usage: gen-certs <dir> <name>...");
+        exit(2);
+    };
+    let dir = Path::new(dir);
+    if dir.join("ca.pem").exists() {
+        eprintln!(
+            "{} already has a ca.pem; use an empty directory",
+            dir.display()
+        );
+        exit(1);
+    }
+    if let Err(e) = generate(dir, names) {
+        eprintln!("Failed to generate certificates: {}", e);
+        exit(1);
+    }
+    println!("Certificates written to {}", dir.display());
+}
+
+fn generate(dir: &Path, names: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+
+    let mut ca_params = CertificateParams::new(Vec::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "Estoque local CA");
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca_key = KeyPair::generate()?;
+    let ca = ca_params.self_signed(&ca_key)?;
+    fs::write(dir.join("ca.pem"), ca.pem())?;
+
+    for name in names {
+        let mut params = CertificateParams::new(vec![
+            name.clone(),
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+        ])?;
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.use_authority_key_identifier_extension = true;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &ca, &ca_key)?;
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem())?;
+        fs::write(dir.join(format!("{}-key.pem", name)), key.serialize_pem())?;
+    }
+    Ok(())
+}
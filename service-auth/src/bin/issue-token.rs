@@ -0,0 +1,40 @@
+//! Prints a token signed with `AUTH_SECRET`, for operators and for testing:
+//!
+//! `issue-token <sub> <role> [ttl_secs]`, e.g. `issue-token alice operator 3600`.
+
+use service_auth::{Role, TokenKey, SECRET_VAR};
+use std::process::exit;
+use std::time::Duration;
+
+const DEFAULT_TTL_SECS: u64 = 8 * 60 * 60;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (Some(sub), Some(role)) = (args.first(), args.get(1)) else {
+        eprintln!("usage: issue-token <sub> <role> [ttl_secs]");
+        exit(2);
+    };
+    let role: Role = role.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(2);
+    });
+    let ttl_secs = match args.get(2).map(|ttl| ttl.parse::<u64>()) {
+        None => DEFAULT_TTL_SECS,
+        Some(Ok(ttl)) if ttl > 0 => ttl,
+        Some(_) => {
+            eprintln!("ttl_secs must be a positive number of seconds");
+            exit(2);
+        }
+    };
+
+    let Some(secret) = std::env::var_os(SECRET_VAR) else {
+        eprintln!("{} is not set", SECRET_VAR);
+        exit(1);
+    };
+    let key = TokenKey::new(secret.as_encoded_bytes()).unwrap_or_else(|e| {
+        eprintln!("{}: {}", SECRET_VAR, e);
+        exit(1);
+    });
+    let (token, _) = key.issue(sub, role, Duration::from_secs(ttl_secs));
+    println!("{}", token);
+}
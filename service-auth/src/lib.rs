@@ -0,0 +1,95 @@
+//! Service identities and operator access, shared by every service.
+//!
+//! Callers present `Authorization: Bearer <token>`, a token signed with the
+//! `AUTH_SECRET` every service is started with. Each service signs its own
+//! token at startup (renewing it before it expires) and sends it on every
+//! call made through the typed clients; operators get theirs from the
+//! `issue-token` tool. The `authenticate` middleware checks the token and the
+//! handlers decide, through `Caller`, which roles may do what. Since every
+//! service holds the secret, any of them can sign a token for any role and
+//! id: the roles guard against mistakes, not against a compromised service.
+//!
+//! Without `AUTH_SECRET` authentication is off and every call is accepted, as
+//! before.
+//!
+//! The `Tls` setup adds optional HTTPS listeners and client certificates on
+//! the calls between services (mutual TLS), with certificates from a local
+//! CA made by the `gen-certs` tool (built with the `gen-certs` feature).
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+mod actix;
+mod tls;
+mod token;
+
+pub use actix::{authenticate, Caller};
+pub use tls::{scheme, Tls, TlsFiles};
+pub use token::{Claims, Role, TokenError, TokenKey, MIN_SECRET_LEN};
+
+pub const SECRET_VAR: &str = "AUTH_SECRET";
+
+/// Lifetime of the tokens services sign for themselves.
+const SERVICE_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+/// A token this close to expiring is replaced before it is sent.
+const SERVICE_TOKEN_RENEW_BEFORE: Duration = Duration::from_secs(5 * 60);
+
+struct Auth {
+    key: TokenKey,
+    sub: String,
+    role: Role,
+    token: Mutex<(String, Claims)>,
+}
+
+static AUTH: OnceLock<Auth> = OnceLock::new();
+
+/// Reads `AUTH_SECRET` and sets up this process as `sub` with `role`.
+/// Authentication stays off when the variable is not set.
+pub fn init(sub: &str, role: Role) -> Result<(), String> {
+    let Some(secret) = std::env::var_os(SECRET_VAR) else {
+        tracing::warn!("{} is not set; authentication is disabled", SECRET_VAR);
+        return Ok(());
+    };
+    let key =
+        TokenKey::new(secret.as_encoded_bytes()).map_err(|e| format!("{}: {}", SECRET_VAR, e))?;
+    let token = key.issue(sub, role, SERVICE_TOKEN_TTL);
+    let auth = Auth {
+        key,
+        sub: sub.to_string(),
+        role,
+        token: Mutex::new(token),
+    };
+    AUTH.set(auth)
+        .map_err(|_| "authentication is already set up".to_string())?;
+    tracing::info!("Authentication enabled as {} ({:?})", sub, role);
+    Ok(())
+}
+
+fn key() -> Option<&'static TokenKey> {
+    AUTH.get().map(|auth| &auth.key)
+}
+
+/// Adds this service's `Authorization` header to an outbound request. Does
+/// nothing when authentication is off.
+pub fn inject_token(headers: &mut HeaderMap) {
+    let Some(auth) = AUTH.get() else {
+        return;
+    };
+    let mut token = auth.token.lock().unwrap();
+    let renew_at = token.1.exp - SERVICE_TOKEN_RENEW_BEFORE.as_secs() as i64;
+    if chrono::Utc::now().timestamp() >= renew_at {
+        *token = auth.key.issue(&auth.sub, auth.role, SERVICE_TOKEN_TTL);
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token.0)) {
+        headers.insert(AUTHORIZATION, value);
+    }
+}
+
+/// `Authorization` header of this service, for requests sent without the
+/// typed clients.
+pub fn auth_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    inject_token(&mut headers);
+    headers
+}
@@ -0,0 +1,150 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const CERT_VAR: &str = "TLS_CERT";
+pub const KEY_VAR: &str = "TLS_KEY";
+pub const CA_VAR: &str = "TLS_CA";
+
+/// PEM files of a service's TLS setup. `cert` and `key` are the service's
+/// own certificate, served to clients and presented as client certificate to
+/// other services. With `ca`, peers are verified against that CA instead of
+/// the public roots, and clients must present a certificate it signed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// Reads `TLS_CERT`, `TLS_KEY` and `TLS_CA`. `None` when neither the
+    /// certificate nor the key is set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let var = |name| std::env::var_os(name).map(PathBuf::from);
+        match (var(CERT_VAR), var(KEY_VAR)) {
+            (Some(cert), Some(key)) => Ok(Some(TlsFiles {
+                cert,
+                key,
+                ca: var(CA_VAR),
+            })),
+            (None, None) if var(CA_VAR).is_none() => Ok(None),
+            _ => Err(format!(
+                "{} and {} must be set together, and {} needs both",
+                CERT_VAR, KEY_VAR, CA_VAR
+            )),
+        }
+    }
+
+    /// Reads and checks every file, so a bad path fails at startup instead of
+    /// on the first connection.
+    pub fn load(&self) -> Result<Tls, String> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+        };
+        let cert_pem = read(&self.cert)?;
+        let key_pem = read(&self.key)?;
+        let certs = parse_certs(&self.cert, &cert_pem)?;
+        let key = PrivateKeyDer::from_pem_slice(&key_pem)
+            .map_err(|e| format!("invalid private key in {}: {}", self.key.display(), e))?;
+
+        let (ca_certs, ca_pem) = match &self.ca {
+            Some(ca) => {
+                let ca_pem = read(ca)?;
+                (parse_certs(ca, &ca_pem)?, Some(ca_pem))
+            }
+            None => (Vec::new(), None),
+        };
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = if ca_certs.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            for cert in &ca_certs {
+                roots.add(cert.clone()).map_err(|e| e.to_string())?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                .build()
+                .map_err(|e| format!("invalid CA: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        };
+        let mut server = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("invalid certificate or key: {}", e))?;
+        server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let identity = reqwest::Identity::from_pem(&[cert_pem, key_pem].join(&b'\n'))
+            .map_err(|e| format!("invalid client identity: {}", e))?;
+        let roots = match ca_pem {
+            Some(pem) => reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("invalid CA: {}", e))?,
+            None => Vec::new(),
+        };
+
+        Ok(Tls {
+            server,
+            identity,
+            roots,
+        })
+    }
+}
+
+fn parse_certs(path: &Path, pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate in {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Loaded TLS setup of a service, for its listener and its HTTP clients.
+#[derive(Clone)]
+pub struct Tls {
+    server: ServerConfig,
+    identity: reqwest::Identity,
+    roots: Vec<reqwest::Certificate>,
+}
+
+impl Tls {
+    /// TLS setup named by `TLS_CERT`, `TLS_KEY` and `TLS_CA`, if any.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        TlsFiles::from_env()?.map(|files| files.load()).transpose()
+    }
+
+    pub fn server_config(&self) -> ServerConfig {
+        self.server.clone()
+    }
+
+    /// Makes `builder` present this service's certificate and, when a CA is
+    /// configured, trust only that CA.
+    pub fn configure_client(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        let mut builder = builder.use_rustls_tls().identity(self.identity.clone());
+        if !self.roots.is_empty() {
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in &self.roots {
+                builder = builder.add_root_certificate(cert.clone());
+            }
+        }
+        builder
+    }
+}
+
+/// URL scheme of a listener with or without TLS.
+pub fn scheme(tls: Option<&Tls>) -> &'static str {
+    if tls.is_some() {
+        "https"
+    } else {
+        "http"
+    }
+}
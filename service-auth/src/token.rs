@@ -0,0 +1,160 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shortest `AUTH_SECRET` accepted, in bytes.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// What a caller is allowed to do.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Cd,
+    Hub,
+    /// A Service Discovery replica, for replication between peers.
+    Discovery,
+    /// A person administering the system: catalog, reorder policies and
+    /// product requests.
+    Operator,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown role {:?}; use cd, hub, discovery or operator", s))
+    }
+}
+
+/// Identity carried by a token: who the caller is (`sub`), its role and the
+/// Unix time the token stops being accepted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Role,
+    pub exp: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "malformed token"),
+            TokenError::BadSignature => write!(f, "invalid token signature"),
+            TokenError::Expired => write!(f, "token expired"),
+        }
+    }
+}
+
+/// Shared secret that signs and verifies tokens. A token is
+/// `base64url(claims JSON).base64url(HMAC-SHA256 of the first part)`.
+#[derive(Clone)]
+pub struct TokenKey {
+    secret: Vec<u8>,
+}
+
+impl TokenKey {
+    pub fn new(secret: &[u8]) -> Result<Self, String> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(format!(
+                "secret must be at least {} bytes long",
+                MIN_SECRET_LEN
+            ));
+        }
+        Ok(TokenKey {
+            secret: secret.to_vec(),
+        })
+    }
+
+    pub fn issue(&self, sub: &str, role: Role, ttl: Duration) -> (String, Claims) {
+        let claims = Claims {
+            sub: sub.to_string(),
+            role,
+            exp: Utc::now().timestamp() + ttl.as_secs() as i64,
+        };
+        let payload =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("claims serialize"));
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        (format!("{}.{}", payload, signature), claims)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let (payload, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::BadSignature)?;
+
+        let claims: Claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(TokenError::Malformed)?;
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> TokenKey {
+        TokenKey::new(&[7; MIN_SECRET_LEN]).unwrap()
+    }
+
+    #[test]
+    fn verifies_its_own_tokens() {
+        let (token, claims) = key().issue("cd_alpha", Role::Cd, Duration::from_secs(60));
+        assert_eq!(key().verify(&token), Ok(claims));
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let (token, _) = key().issue("cd_alpha", Role::Cd, Duration::from_secs(60));
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged_claims = Claims {
+            sub: "cd_alpha".to_string(),
+            role: Role::Operator,
+            exp: i64::MAX,
+        };
+        let forged_payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged_claims).unwrap());
+        let forged = format!("{}.{}", forged_payload, signature);
+        assert_eq!(key().verify(&forged), Err(TokenError::BadSignature));
+
+        let other_key = TokenKey::new(&[8; MIN_SECRET_LEN]).unwrap();
+        assert_eq!(other_key.verify(&token), Err(TokenError::BadSignature));
+        assert_eq!(key().verify("no-dot"), Err(TokenError::Malformed));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let (token, _) = key().issue("cd_alpha", Role::Cd, Duration::ZERO);
+        assert_eq!(key().verify(&token), Err(TokenError::Expired));
+    }
+}
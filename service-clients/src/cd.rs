@@ -0,0 +1,147 @@
+use super::{endpoint, fetch_json, fetch_text, parse_base_url, valuation_url, ClientError};
+use common_models::{
+    InventorySnapshot, Product, ProductRequest, ProductRequestStatus, ReceiveRequest, Reservation,
+    ReservationRequest, ServiceInfoLookup, TransferRequest, ValuationQuery, ValuationReport,
+};
+use reqwest::{Client, RequestBuilder, Url};
+use std::time::Duration;
+
+/// Client for one CD's API.
+#[derive(Clone)]
+pub struct CdClient {
+    http: Client,
+    base_url: Url,
+    timeout: Option<Duration>,
+}
+
+impl CdClient {
+    pub fn new(http: Client, base_url: &str) -> Result<Self, ClientError> {
+        Ok(CdClient {
+            http,
+            base_url: parse_base_url(base_url)?,
+            timeout: None,
+        })
+    }
+
+    /// Client for the CD at the address Service Discovery returned for it.
+    pub fn for_service(http: Client, service: &ServiceInfoLookup) -> Result<Self, ClientError> {
+        Self::new(
+            http,
+            &format!("{}://{}:{}", service.scheme, service.ip, service.port),
+        )
+    }
+
+    /// Overrides the HTTP client's timeout for every request of this client.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        self.base_url.as_str().trim_end_matches('/')
+    }
+
+    pub async fn inventory(&self) -> Result<Vec<Product>, ClientError> {
+        self.get_json(&["inventory"]).await
+    }
+
+    pub async fn product(&self, product_code: &str) -> Result<Product, ClientError> {
+        self.get_json(&["inventory", product_code]).await
+    }
+
+    pub async fn stock_snapshot(&self) -> Result<InventorySnapshot, ClientError> {
+        self.get_json(&["stock_snapshot"]).await
+    }
+
+    /// This CD's valuation as JSON; `query.format` is ignored.
+    pub async fn valuation_report(
+        &self,
+        query: &ValuationQuery,
+    ) -> Result<ValuationReport, ClientError> {
+        let url = valuation_url(&self.base_url, query);
+        fetch_json(self.request(self.http.get(url.clone())), &url).await
+    }
+
+    pub async fn reserve(&self, request: &ReservationRequest) -> Result<Reservation, ClientError> {
+        let url = endpoint(&self.base_url, &["reservations"]);
+        fetch_json(
+            self.request(self.http.post(url.clone()).json(request)),
+            &url,
+        )
+        .await
+    }
+
+    pub async fn reservation(&self, reservation_id: &str) -> Result<Reservation, ClientError> {
+        self.get_json(&["reservations", reservation_id]).await
+    }
+
+    /// Commits a reservation. Repeating the call after a lost answer returns
+    /// the same committed reservation.
+    pub async fn commit_reservation(
+        &self,
+        reservation_id: &str,
+    ) -> Result<Reservation, ClientError> {
+        let url = endpoint(&self.base_url, &["reservations", reservation_id, "commit"]);
+        fetch_json(self.request(self.http.post(url.clone())), &url).await
+    }
+
+    pub async fn abort_reservation(
+        &self,
+        reservation_id: &str,
+    ) -> Result<Reservation, ClientError> {
+        let url = endpoint(&self.base_url, &["reservations", reservation_id, "abort"]);
+        fetch_json(self.request(self.http.post(url.clone())), &url).await
+    }
+
+    pub async fn transfer_product(&self, request: &TransferRequest) -> Result<String, ClientError> {
+        let url = endpoint(&self.base_url, &["transfer_product"]);
+        fetch_text(
+            self.request(self.http.post(url.clone()).json(request)),
+            &url,
+        )
+        .await
+    }
+
+    pub async fn receive_product(&self, request: &ReceiveRequest) -> Result<String, ClientError> {
+        let url = endpoint(&self.base_url, &["receive_product"]);
+        fetch_text(
+            self.request(self.http.post(url.clone()).json(request)),
+            &url,
+        )
+        .await
+    }
+
+    pub async fn create_product_request(
+        &self,
+        request: &ProductRequest,
+    ) -> Result<ProductRequestStatus, ClientError> {
+        let url = endpoint(&self.base_url, &["requests"]);
+        fetch_json(
+            self.request(self.http.post(url.clone()).json(request)),
+            &url,
+        )
+        .await
+    }
+
+    pub async fn product_request(
+        &self,
+        request_id: &str,
+    ) -> Result<ProductRequestStatus, ClientError> {
+        self.get_json(&["requests", request_id]).await
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        segments: &[&str],
+    ) -> Result<T, ClientError> {
+        let url = endpoint(&self.base_url, segments);
+        fetch_json(self.request(self.http.get(url.clone())), &url).await
+    }
+
+    fn request(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use super::{endpoint, fetch_json, fetch_text, parse_base_url, ClientError};
+use common_models::{ServiceInfo, ServiceInfoLookup, ServiceQuery};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Client for a set of Service Discovery replicas. Each call goes to the
+/// replica that answered last and fails over to the next one only when a
+/// replica cannot be reached; an HTTP error status is returned as is.
+pub struct DiscoveryClient {
+    http: Client,
+    base_urls: Vec<Url>,
+    preferred: AtomicUsize,
+}
+
+impl DiscoveryClient {
+    pub fn new<S: AsRef<str>>(http: Client, base_urls: &[S]) -> Result<Self, ClientError> {
+        if base_urls.is_empty() {
+            return Err(ClientError::InvalidUrl(
+                "at least one Service Discovery URL is required".to_string(),
+            ));
+        }
+        let base_urls = base_urls
+            .iter()
+            .map(|url| parse_base_url(url.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(DiscoveryClient {
+            http,
+            base_urls,
+            preferred: AtomicUsize::new(0),
+        })
+    }
+
+    pub async fn register(&self, service: &ServiceInfo) -> Result<(), ClientError> {
+        self.failover_text(&["register"], |http, url| http.post(url).json(service))
+            .await
+            .map(drop)
+    }
+
+    pub async fn deregister(&self, service_id: &str) -> Result<(), ClientError> {
+        self.failover_text(&["register", service_id], |http, url| http.delete(url))
+            .await
+            .map(drop)
+    }
+
+    /// `Err` with status 404 means the replica does not know the service and
+    /// it should register again.
+    pub async fn heartbeat(&self, service_id: &str) -> Result<(), ClientError> {
+        self.failover_text(&["heartbeat", service_id], |http, url| http.post(url))
+            .await
+            .map(drop)
+    }
+
+    pub async fn lookup(&self, service_id: &str) -> Result<ServiceInfoLookup, ClientError> {
+        self.failover_json(&["lookup", service_id], |http, url| http.get(url))
+            .await
+    }
+
+    pub async fn lookup_all(&self) -> Result<Vec<ServiceInfoLookup>, ClientError> {
+        self.lookup_services(&ServiceQuery::default()).await
+    }
+
+    /// Registered services of the type and with the tags in `query`.
+    pub async fn lookup_services(
+        &self,
+        query: &ServiceQuery,
+    ) -> Result<Vec<ServiceInfoLookup>, ClientError> {
+        self.failover_json(&["lookup_all"], |http, url| http.get(url).query(query))
+            .await
+    }
+
+    async fn failover_json<T, F>(&self, segments: &[&str], build: F) -> Result<T, ClientError>
+    where
+        T: DeserializeOwned,
+        F: Fn(&Client, Url) -> RequestBuilder,
+    {
+        let mut last_error = None;
+        for index in self.replica_order() {
+            let url = endpoint(&self.base_urls[index], segments);
+            match fetch_json(build(&self.http, url.clone()), &url).await {
+                Err(e) if e.is_unreachable() => last_error = Some(e),
+                result => {
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return result;
+                }
+            }
+        }
+        Err(last_error.expect("at least one Service Discovery URL is configured"))
+    }
+
+    async fn failover_text<F>(&self, segments: &[&str], build: F) -> Result<String, ClientError>
+    where
+        F: Fn(&Client, Url) -> RequestBuilder,
+    {
+        let mut last_error = None;
+        for index in self.replica_order() {
+            let url = endpoint(&self.base_urls[index], segments);
+            match fetch_text(build(&self.http, url.clone()), &url).await {
+                Err(e) if e.is_unreachable() => last_error = Some(e),
+                result => {
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return result;
+                }
+            }
+        }
+        Err(last_error.expect("at least one Service Discovery URL is configured"))
+    }
+
+    /// Replica indexes starting with the one that answered last.
+    fn replica_order(&self) -> impl Iterator<Item = usize> {
+        let count = self.base_urls.len();
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        (0..count).map(move |offset| (preferred + offset) % count)
+    }
+}
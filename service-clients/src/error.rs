@@ -0,0 +1,75 @@
+use common_models::{ApiError, ErrorCode};
+use reqwest::StatusCode;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// A base URL given to a client could not be used.
+    InvalidUrl(String),
+    /// The request to this URL did not finish within the timeout.
+    Timeout(String),
+    /// The request could not be sent or the connection failed.
+    Transport(reqwest::Error),
+    /// The service answered with an error in the shared `ApiError` format.
+    Api { status: StatusCode, error: ApiError },
+    /// The service answered with an unexpected status and no `ApiError`.
+    Status { status: StatusCode, body: String },
+    /// The answer was not the expected JSON.
+    Decode(String),
+}
+
+impl ClientError {
+    /// The HTTP status of an `Api` or `Status` error.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Api { status, .. } | ClientError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// The error code the service answered with, if any.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Api { error, .. } => Some(error.code),
+            _ => None,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ClientError::Timeout(_))
+    }
+
+    /// True when the request never got an answer, so it may or may not have
+    /// been processed by the service.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, ClientError::Timeout(_) | ClientError::Transport(_))
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(e) => write!(f, "invalid URL {}", e),
+            ClientError::Timeout(url) => write!(f, "no answer from {} in time", url),
+            ClientError::Transport(e) => write!(f, "{}", e),
+            ClientError::Api { error, .. } => write!(f, "{}", error),
+            ClientError::Status { status, body } if body.is_empty() => write!(f, "{}", status),
+            ClientError::Status { status, body } => write!(f, "{}: {}", status, body),
+            ClientError::Decode(e) => write!(f, "unexpected response from {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Passes an upstream `ApiError` through unchanged; transport and protocol
+/// failures become `UPSTREAM_UNAVAILABLE` or `UPSTREAM_ERROR`.
+impl From<ClientError> for ApiError {
+    fn from(error: ClientError) -> Self {
+        match error {
+            ClientError::Api { error, .. } => error,
+            e if e.is_unreachable() => ApiError::new(ErrorCode::UpstreamUnavailable, e.to_string()),
+            e => ApiError::new(ErrorCode::UpstreamError, e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use super::{
+    check_status, decode, endpoint, fetch_json, parse_base_url, send, valuation_url, ClientError,
+};
+use common_models::{
+    AvailabilityResponse, ErrorCode, InventorySnapshot, Product, ProductPage, StockEventAck,
+    StockEventBatch, StockOverview, ValuationQuery, ValuationReport,
+};
+use reqwest::{Client, Url};
+use serde::Serialize;
+
+/// Client for the Hub Service API.
+#[derive(Clone)]
+pub struct HubClient {
+    http: Client,
+    base_url: Url,
+}
+
+impl HubClient {
+    pub fn new(http: Client, base_url: &str) -> Result<Self, ClientError> {
+        Ok(HubClient {
+            http,
+            base_url: parse_base_url(base_url)?,
+        })
+    }
+
+    /// CDs holding `quantity` of `product_code`; with `partial`, also those
+    /// holding less.
+    pub async fn who_has_product(
+        &self,
+        product_code: &str,
+        quantity: u32,
+        partial: bool,
+    ) -> Result<AvailabilityResponse, ClientError> {
+        let quantity = quantity.to_string();
+        let mut url = endpoint(
+            &self.base_url,
+            &["who_has_product", product_code, &quantity],
+        );
+        if partial {
+            url.set_query(Some("partial=true"));
+        }
+        fetch_json(self.http.get(url.clone()), &url).await
+    }
+
+    pub async fn register_product(&self, product: &Product) -> Result<Product, ClientError> {
+        let url = endpoint(&self.base_url, &["products"]);
+        fetch_json(self.http.post(url.clone()).json(product), &url).await
+    }
+
+    pub async fn get_product(&self, product_code: &str) -> Result<Product, ClientError> {
+        let url = endpoint(&self.base_url, &["products", product_code]);
+        fetch_json(self.http.get(url.clone()), &url).await
+    }
+
+    pub async fn list_products(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<ProductPage, ClientError> {
+        let mut url = endpoint(&self.base_url, &["products"]);
+        url.query_pairs_mut()
+            .append_pair("offset", &offset.to_string())
+            .append_pair("limit", &limit.to_string());
+        fetch_json(self.http.get(url.clone()), &url).await
+    }
+
+    pub async fn stock_overview(&self) -> Result<StockOverview, ClientError> {
+        let url = endpoint(&self.base_url, &["stock"]);
+        fetch_json(self.http.get(url.clone()), &url).await
+    }
+
+    pub async fn product_stock(&self, product_code: &str) -> Result<StockOverview, ClientError> {
+        let url = endpoint(&self.base_url, &["stock", product_code]);
+        fetch_json(self.http.get(url.clone()), &url).await
+    }
+
+    /// Network-wide valuation as JSON; `query.format` is ignored.
+    pub async fn valuation_report(
+        &self,
+        query: &ValuationQuery,
+    ) -> Result<ValuationReport, ClientError> {
+        let url = valuation_url(&self.base_url, query);
+        fetch_json(self.http.get(url.clone()), &url).await
+    }
+
+    /// Publishes stock events. A gap or an unknown CD is not an error: the
+    /// hub answers a `CONFLICT` with the last event it has in the details, and
+    /// that ack is returned.
+    pub async fn publish_stock_events(
+        &self,
+        batch: &StockEventBatch,
+    ) -> Result<StockEventAck, ClientError> {
+        self.post_stock_update("stock_events", batch).await
+    }
+
+    pub async fn publish_stock_snapshot(
+        &self,
+        snapshot: &InventorySnapshot,
+    ) -> Result<StockEventAck, ClientError> {
+        self.post_stock_update("stock_snapshot", snapshot).await
+    }
+
+    async fn post_stock_update<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<StockEventAck, ClientError> {
+        let url = endpoint(&self.base_url, &[path]);
+        let response = send(self.http.post(url.clone()).json(body), &url).await?;
+        match check_status(response, &[]).await {
+            Ok(response) => decode(response).await,
+            Err(e) => conflict_ack(&e).ok_or(e),
+        }
+    }
+}
+
+/// The ack carried by the hub's `CONFLICT` answer to a batch it cannot apply.
+fn conflict_ack(error: &ClientError) -> Option<StockEventAck> {
+    match error {
+        ClientError::Api { error, .. } if error.code == ErrorCode::Conflict => {
+            serde_json::from_value(error.details.clone()?).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_models::ApiError;
+    use reqwest::StatusCode;
+
+    fn api_error(body: &str) -> ClientError {
+        ClientError::Api {
+            status: StatusCode::CONFLICT,
+            error: serde_json::from_str::<ApiError>(body).unwrap(),
+        }
+    }
+
+    #[test]
+    fn conflict_carries_the_hub_ack() {
+        let gap = api_error(r#"{"code":"CONFLICT","message":"gap","details":{"last_seq":3}}"#);
+        assert_eq!(conflict_ack(&gap).unwrap().last_seq, Some(3));
+
+        let unknown =
+            api_error(r#"{"code":"CONFLICT","message":"no snapshot","details":{"last_seq":null}}"#);
+        assert_eq!(conflict_ack(&unknown).unwrap().last_seq, None);
+    }
+
+    #[test]
+    fn other_errors_are_not_acks() {
+        let no_details = api_error(r#"{"code":"CONFLICT","message":"gap"}"#);
+        assert!(conflict_ack(&no_details).is_none());
+
+        let forbidden =
+            api_error(r#"{"code":"FORBIDDEN","message":"no","details":{"last_seq":3}}"#);
+        assert!(conflict_ack(&forbidden).is_none());
+    }
+}
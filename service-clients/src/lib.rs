@@ -0,0 +1,153 @@
+//! Typed HTTP clients for the Service Discovery, Hub and CD APIs, built on
+//! the `common-models` types.
+
+use common_models::{ApiError, RankBy, ValuationQuery};
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use service_auth::Tls;
+use std::time::Duration;
+use tracing::Instrument;
+
+mod cd;
+mod discovery;
+mod error;
+mod hub;
+
+pub use cd::CdClient;
+pub use discovery::DiscoveryClient;
+pub use error::ClientError;
+pub use hub::HubClient;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Builds a `reqwest::Client` with the given overall request timeout,
+/// presenting the service's client certificate when `tls` is set. Share one
+/// between the typed clients so they reuse connections.
+pub fn http_client(timeout: Duration, tls: Option<&Tls>) -> Client {
+    let mut builder = Client::builder()
+        .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+        .timeout(timeout);
+    if let Some(tls) = tls {
+        builder = tls.configure_client(builder);
+    }
+    builder.build().expect("Failed to build HTTP client")
+}
+
+/// Parses and normalizes a base URL such as `http://127.0.0.1:8082/`.
+fn parse_base_url(base_url: &str) -> Result<Url, ClientError> {
+    let url = Url::parse(base_url.trim_end_matches('/'))
+        .map_err(|e| ClientError::InvalidUrl(format!("{}: {}", base_url, e)))?;
+    if url.cannot_be_a_base() || !matches!(url.scheme(), "http" | "https") {
+        return Err(ClientError::InvalidUrl(format!(
+            "{}: must be an http:// or https:// URL",
+            base_url
+        )));
+    }
+    Ok(url)
+}
+
+/// `base` followed by `segments`, each percent-encoded as one path segment.
+fn endpoint(base: &Url, segments: &[&str]) -> Url {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .expect("base URLs are checked by parse_base_url")
+        .pop_if_empty()
+        .extend(segments);
+    url
+}
+
+/// `/reports/valuation` with the ranking options of `query`, always as JSON.
+fn valuation_url(base: &Url, query: &ValuationQuery) -> Url {
+    let mut url = endpoint(base, &["reports", "valuation"]);
+    {
+        let mut pairs = url.query_pairs_mut();
+        pairs.append_pair(
+            "by",
+            match query.by {
+                RankBy::Value => "value",
+                RankBy::Quantity => "quantity",
+            },
+        );
+        if let Some(top) = query.top {
+            pairs.append_pair("top", &top.to_string());
+        }
+    }
+    url
+}
+
+/// Sends `request` in a client span whose trace context goes along in the
+/// `traceparent` header, so the service called continues the same trace. The
+/// request carries this service's token when authentication is on.
+async fn send(request: RequestBuilder, url: &Url) -> Result<reqwest::Response, ClientError> {
+    let (client, request) = request.build_split();
+    let mut request = request.map_err(ClientError::Transport)?;
+    let span = tracing::info_span!(
+        "http_client",
+        otel.name = format!("{} {}", request.method(), url.path()),
+        otel.kind = "client",
+        http.request.method = %request.method(),
+        url.full = %url,
+        http.response.status_code = tracing::field::Empty,
+    );
+    telemetry::inject_context(&span, request.headers_mut());
+    service_auth::inject_token(request.headers_mut());
+
+    let response = client
+        .execute(request)
+        .instrument(span.clone())
+        .await
+        .map_err(|e| {
+            if e.is_timeout() {
+                ClientError::Timeout(url.to_string())
+            } else {
+                ClientError::Transport(e)
+            }
+        })?;
+    span.record("http.response.status_code", response.status().as_u16());
+    Ok(response)
+}
+
+/// Turns a non-2xx response (other than those in `accepted`) into
+/// `ClientError::Api` when it carries an `ApiError` body, or
+/// `ClientError::Status` otherwise.
+async fn check_status(
+    response: reqwest::Response,
+    accepted: &[StatusCode],
+) -> Result<reqwest::Response, ClientError> {
+    let status = response.status();
+    if status.is_success() || accepted.contains(&status) {
+        Ok(response)
+    } else {
+        let body = response.text().await.unwrap_or_default();
+        match serde_json::from_str::<ApiError>(&body) {
+            Ok(error) => Err(ClientError::Api { status, error }),
+            Err(_) => Err(ClientError::Status { status, body }),
+        }
+    }
+}
+
+async fn decode<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, ClientError> {
+    let url = response.url().clone();
+    response.json().await.map_err(|e| {
+        if e.is_timeout() {
+            ClientError::Timeout(url.to_string())
+        } else {
+            ClientError::Decode(format!("{}: {}", url, e))
+        }
+    })
+}
+
+/// Sends `request` and decodes a JSON body from a 2xx answer.
+async fn fetch_json<T: DeserializeOwned>(
+    request: RequestBuilder,
+    url: &Url,
+) -> Result<T, ClientError> {
+    decode(check_status(send(request, url).await?, &[]).await?).await
+}
+
+/// Sends `request` and returns the text body of a 2xx answer.
+async fn fetch_text(request: RequestBuilder, url: &Url) -> Result<String, ClientError> {
+    let response = check_status(send(request, url).await?, &[]).await?;
+    response.text().await.map_err(ClientError::Transport)
+}
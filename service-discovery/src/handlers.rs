@@ -1,59 +1,267 @@
+use super::registry::RegistryEntry;
 use super::state::AppState;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
 use chrono::Utc;
-use common_models::{ServiceInfo, ServiceInfoLookup};
+use common_models::{
+    validate_tags, ApiError, ErrorCode, ServiceInfo, ServiceInfoLookup, ServiceQuery, ServiceType,
+};
+use service_auth::{Caller, Role};
+use std::collections::HashMap;
 use std::time::Duration;
 use tokio::time::sleep;
+use tracing::{error, info, warn, Instrument};
 
 pub async fn register_service(
     info: web::Json<ServiceInfo>,
     data: web::Data<AppState>,
+    caller: Caller,
 ) -> impl Responder {
-    let mut services = data.registered_services.lock().unwrap();
+    if let Err(e) = caller.require_self_or_operator(&info.id) {
+        return e.error_response();
+    }
+    // Um serviço só se registra com o tipo do seu token; só operadores
+    // registram qualquer tipo. Sem isso o Hub poderia ser listado como CD.
+    if let Some(claims) = caller.claims() {
+        let allowed = match claims.role {
+            Role::Cd => Some(ServiceType::Cd),
+            Role::Hub => Some(ServiceType::Hub),
+            Role::Discovery => Some(ServiceType::Discovery),
+            Role::Operator => None,
+        };
+        if allowed.is_some_and(|allowed| allowed != info.service_type) {
+            return ApiError::new(
+                ErrorCode::Forbidden,
+                format!(
+                    "{} has role {:?} and cannot register as type {:?}",
+                    claims.sub, claims.role, info.service_type
+                ),
+            )
+            .error_response();
+        }
+    }
+    let problems = validate_tags(&info.tags);
+    if !problems.is_empty() {
+        return ApiError::new(ErrorCode::BadRequest, problems.join("; ")).error_response();
+    }
     let mut service_info = info.clone();
     service_info.last_heartbeat = Utc::now();
-    services.insert(service_info.id.clone(), service_info);
-    println!("Registered/Updated service: {:?}", services.get(&info.id));
+    let (id, entry) = {
+        let mut registry = data.registry.lock().unwrap();
+        let written = registry.put(service_info);
+        if let Err(e) = registry.flush() {
+            error!("Failed to persist registry: {}", e);
+            return ApiError::new(ErrorCode::Internal, "Failed to persist registration")
+                .error_response();
+        }
+        written
+    };
+    info!(service = ?entry.service, "Registered/Updated service {}", id);
+    replicate(&data, HashMap::from([(id, entry)]));
     HttpResponse::Ok().body(format!("Service {} registered successfully", info.id))
 }
 
+pub async fn deregister_service(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    let service_id = path.into_inner();
+    if let Err(e) = caller.require_self_or_operator(&service_id) {
+        return e.error_response();
+    }
+    let removed = {
+        let mut registry = data.registry.lock().unwrap();
+        let removed = registry.remove(&service_id);
+        if let Err(e) = registry.flush() {
+            error!("Failed to persist registry: {}", e);
+            return ApiError::new(ErrorCode::Internal, "Failed to persist deregistration")
+                .error_response();
+        }
+        removed
+    };
+
+    if let Some((id, entry)) = removed {
+        info!("Deregistered service {}", service_id);
+        replicate(&data, HashMap::from([(id, entry)]));
+        HttpResponse::Ok().body(format!("Service {} deregistered successfully", service_id))
+    } else {
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("Service {} not found", service_id),
+        )
+        .error_response()
+    }
+}
+
 pub async fn lookup_service(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
     let service_id = path.into_inner();
-    let services = data.registered_services.lock().unwrap();
+    let registry = data.registry.lock().unwrap();
 
-    if let Some(info) = services.get(&service_id) {
-        HttpResponse::Ok().json(ServiceInfoLookup {
-            id: info.id.clone(),
-            ip: info.ip.clone(),
-            port: info.port,
-        })
+    if let Some(info) = registry.get(&service_id) {
+        HttpResponse::Ok().json(ServiceInfoLookup::from(info))
     } else {
-        HttpResponse::NotFound().body(format!("Service {} not found", service_id))
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("Service {} not found", service_id),
+        )
+        .error_response()
     }
 }
 
-pub async fn lookup_all_services(data: web::Data<AppState>) -> impl Responder {
-    let services = data.registered_services.lock().unwrap();
-    let service_infos: Vec<ServiceInfoLookup> = services
-        .values()
-        .map(|info| ServiceInfoLookup {
-            id: info.id.clone(),
-            ip: info.ip.clone(),
-            port: info.port,
-        })
+// GET /lookup_all: Serviços registrados, opcionalmente filtrados por tipo e tags.
+pub async fn lookup_all_services(
+    query: web::Query<ServiceQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let registry = data.registry.lock().unwrap();
+    let service_infos: Vec<ServiceInfoLookup> = registry
+        .services()
+        .filter(|info| query.matches(info))
+        .map(ServiceInfoLookup::from)
         .collect();
     HttpResponse::Ok().json(service_infos)
 }
 
-pub async fn heartbeat(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+pub async fn heartbeat(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
     let service_id = path.into_inner();
-    let mut services = data.registered_services.lock().unwrap();
+    if let Err(e) = caller.require_self_or_operator(&service_id) {
+        return e.error_response();
+    }
+    let written = {
+        let mut registry = data.registry.lock().unwrap();
+        registry.get(&service_id).cloned().map(|mut service_info| {
+            service_info.last_heartbeat = Utc::now();
+            registry.put(service_info)
+        })
+    };
 
-    if let Some(service_info) = services.get_mut(&service_id) {
-        service_info.last_heartbeat = Utc::now();
+    if let Some((id, entry)) = written {
+        data.metrics
+            .heartbeats
+            .with_label_values(&["accepted"])
+            .inc();
+        replicate(&data, HashMap::from([(id, entry)]));
         HttpResponse::Ok().body(format!("Heartbeat received for {}", service_id))
     } else {
-        HttpResponse::NotFound().body(format!("Service {} not found for heartbeat", service_id))
+        data.metrics
+            .heartbeats
+            .with_label_values(&["unknown"])
+            .inc();
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("Service {} not found for heartbeat", service_id),
+        )
+        .error_response()
+    }
+}
+
+// GET /metrics: Métricas no formato do Prometheus.
+pub async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+    let registered = data.registry.lock().unwrap().services().count();
+    data.metrics.registered_services.set(registered as i64);
+    telemetry::metrics_response(&data.metrics.registry)
+}
+
+/// Full registry of this replica, tombstones included, for peers to merge.
+pub async fn get_replication_entries(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Discovery]) {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(data.registry.lock().unwrap().entries())
+}
+
+/// Entries pushed by a peer replica after a local write on its side.
+pub async fn merge_replication_entries(
+    entries: web::Json<HashMap<String, RegistryEntry>>,
+    data: web::Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(e) = caller.require(&[Role::Discovery]) {
+        return e.error_response();
+    }
+    let applied = data.registry.lock().unwrap().merge(entries.into_inner());
+    HttpResponse::Ok().json(serde_json::json!({ "applied": applied }))
+}
+
+/// Pushes local writes to every peer in the background. A peer that misses
+/// the push catches up through `sync_with_peers`.
+fn replicate(data: &web::Data<AppState>, entries: HashMap<String, RegistryEntry>) {
+    for peer_url in &data.peer_urls {
+        let client = data.http_client.clone();
+        let url = format!("{}/replication/entries", peer_url);
+        let entries = entries.clone();
+        let request = client
+            .post(&url)
+            .headers(telemetry::trace_headers())
+            .headers(service_auth::auth_headers())
+            .json(&entries);
+        tokio::spawn(
+            async move {
+                match request.send().await {
+                    Ok(resp) if resp.status().is_success() => {}
+                    Ok(resp) => warn!("Peer {} refused replication: Status {}", url, resp.status()),
+                    Err(e) => warn!("Failed to replicate to {}: {}", url, e),
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Anti-entropy: periodically pulls the full registry of every peer and
+/// merges it, so a replica that was down or partitioned converges again.
+pub async fn sync_with_peers(state: web::Data<AppState>) {
+    let sync_interval = Duration::from_secs(5);
+
+    loop {
+        for peer_url in &state.peer_urls {
+            let url = format!("{}/replication/entries", peer_url);
+            let entries = match state
+                .http_client
+                .get(&url)
+                .headers(service_auth::auth_headers())
+                .send()
+                .await
+            {
+                Ok(resp) if resp.status().is_success() => {
+                    resp.json::<HashMap<String, RegistryEntry>>().await
+                }
+                Ok(resp) => {
+                    warn!("Peer {} refused sync: Status {}", peer_url, resp.status());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Peer {} unreachable: {}", peer_url, e);
+                    continue;
+                }
+            };
+            match entries {
+                Ok(entries) => {
+                    let applied = state.registry.lock().unwrap().merge(entries);
+                    if applied > 0 {
+                        info!("Merged {} entries from {}", applied, peer_url);
+                    }
+                }
+                Err(e) => warn!("Invalid registry from {}: {}", peer_url, e),
+            }
+        }
+        sleep(sync_interval).await;
+    }
+}
+
+/// Saves the registry to disk about once a second when it changed. Register
+/// and deregister flush before answering; this catches heartbeats and
+/// entries merged from peers, which a crash can afford to lose.
+pub async fn persist_registry(state: web::Data<AppState>) {
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        if let Err(e) = state.registry.lock().unwrap().flush() {
+            error!("Failed to persist registry: {}", e);
+        }
     }
 }
 
@@ -63,22 +271,29 @@ pub async fn cleanup_inactive_services(state: web::Data<AppState>) {
 
     loop {
         sleep(cleanup_interval).await;
-        let mut services = state.registered_services.lock().unwrap();
-        let now = Utc::now();
-        let mut removed_ids = Vec::new();
-
-        services.retain(|id, info| {
-            if (now - info.last_heartbeat).to_std().unwrap_or_default() > inactivity_threshold {
-                println!("Removing inactive service: {}", id);
-                removed_ids.push(id.clone());
-                false
-            } else {
-                true
+        let mut removed = HashMap::new();
+        {
+            let mut registry = state.registry.lock().unwrap();
+            let now = Utc::now();
+            let inactive_ids: Vec<String> = registry
+                .services()
+                .filter(|info| {
+                    (now - info.last_heartbeat).to_std().unwrap_or_default() > inactivity_threshold
+                })
+                .map(|info| info.id.clone())
+                .collect();
+
+            for id in inactive_ids {
+                info!("Removing inactive service {}", id);
+                removed.extend(registry.remove(&id));
             }
-        });
+            registry.prune_tombstones();
+        }
 
-        if !removed_ids.is_empty() {
-            println!("Cleaned up: {:?}", removed_ids);
+        if !removed.is_empty() {
+            state.metrics.evictions.inc_by(removed.len() as u64);
+            info!(removed = ?removed.keys().collect::<Vec<_>>(), "Cleaned up inactive services");
+            replicate(&state, removed);
         }
     }
 }
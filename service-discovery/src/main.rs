@@ -1,33 +1,103 @@
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
-use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use tracing::{error, info};
 
 mod handlers;
+mod metrics;
+mod registry;
 mod state;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // Uma réplica isolada em 127.0.0.1:8080 continua sendo o padrão.
+    let bind_address = env::var("DISCOVERY_BIND").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let node_id = env::var("DISCOVERY_ID").unwrap_or_else(|_| bind_address.clone());
+    let data_dir = env::var("DISCOVERY_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            PathBuf::from("data").join(format!("discovery-{}", bind_address.replace(':', "_")))
+        });
+    let peer_urls: Vec<String> = env::var("DISCOVERY_PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .collect();
+
+    let _telemetry = telemetry::init("service-discovery", &node_id);
+    if let Err(e) = service_auth::init(&node_id, service_auth::Role::Discovery) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let tls = match service_auth::Tls::from_env() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("Invalid TLS setup: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let http_client = service_clients::http_client(service_clients::DEFAULT_TIMEOUT, tls.as_ref());
+
+    let registry = match registry::Registry::open(node_id.clone(), data_dir.clone()) {
+        Ok(registry) => registry,
+        Err(e) => {
+            error!("Failed to open registry in {:?}: {}", data_dir, e);
+            std::process::exit(1);
+        }
+    };
+
     let app_state = web::Data::new(state::AppState {
-        registered_services: Arc::new(Mutex::new(HashMap::new())),
+        registry: Arc::new(Mutex::new(registry)),
+        peer_urls,
+        http_client,
+        metrics: metrics::Metrics::new(),
     });
 
-    let cleanup_state = app_state.clone();
-    tokio::spawn(handlers::cleanup_inactive_services(cleanup_state));
+    tokio::spawn(handlers::cleanup_inactive_services(app_state.clone()));
+    tokio::spawn(handlers::persist_registry(app_state.clone()));
+    if !app_state.peer_urls.is_empty() {
+        tokio::spawn(handlers::sync_with_peers(app_state.clone()));
+    }
 
-    let ip = "127.0.0.1";
-    let port = 8080;
+    info!(
+        peers = ?app_state.peer_urls,
+        "Service Discovery {} running on {}://{}",
+        node_id,
+        service_auth::scheme(tls.as_ref()),
+        bind_address
+    );
 
-    println!("Service Discovery running on http://{}:{}", ip, port);
-
-    HttpServer::new(move || {
+    let server_state = app_state.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(app_state.clone())
+            .app_data(server_state.clone())
+            .wrap(from_fn(service_auth::authenticate))
+            .wrap(from_fn(telemetry::trace_id_header))
+            .wrap(telemetry::TracingLogger::default())
+            .configure(common_models::configure_error_responses)
             .service(web::resource("/register").post(handlers::register_service))
+            .service(web::resource("/register/{id}").delete(handlers::deregister_service))
             .service(web::resource("/lookup/{id}").get(handlers::lookup_service))
             .service(web::resource("/lookup_all").get(handlers::lookup_all_services))
             .service(web::resource("/heartbeat/{id}").post(handlers::heartbeat))
-    })
-    .bind(format!("{}:{}", ip, port))?
-    .run()
-    .await
+            .service(web::resource("/metrics").get(handlers::get_metrics))
+            .service(
+                web::resource("/replication/entries")
+                    .get(handlers::get_replication_entries)
+                    .post(handlers::merge_replication_entries),
+            )
+    });
+    let server = match &tls {
+        Some(tls) => server.bind_rustls_0_23(&bind_address, tls.server_config())?,
+        None => server.bind(&bind_address)?,
+    };
+    let result = server.run().await;
+
+    if let Err(e) = app_state.registry.lock().unwrap().flush() {
+        error!("Failed to persist registry: {}", e);
+    }
+    result
 }
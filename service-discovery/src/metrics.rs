@@ -0,0 +1,46 @@
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+
+/// Prometheus metrics of one Service Discovery replica, served on `/metrics`.
+pub struct Metrics {
+    pub registry: Registry,
+    /// Set from the registry on every scrape.
+    pub registered_services: IntGauge,
+    pub evictions: IntCounter,
+    /// Heartbeats by result: `accepted`, or `unknown` for services that are
+    /// not registered.
+    pub heartbeats: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registered_services = IntGauge::new(
+            "discovery_registered_services",
+            "Services currently registered",
+        )
+        .unwrap();
+        let evictions = IntCounter::new(
+            "discovery_evictions_total",
+            "Services removed for missing heartbeats",
+        )
+        .unwrap();
+        let heartbeats = IntCounterVec::new(
+            Opts::new("discovery_heartbeats_total", "Heartbeats received"),
+            &["result"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(registered_services.clone()))
+            .unwrap();
+        registry.register(Box::new(evictions.clone())).unwrap();
+        registry.register(Box::new(heartbeats.clone())).unwrap();
+
+        Metrics {
+            registry,
+            registered_services,
+            evictions,
+            heartbeats,
+        }
+    }
+}
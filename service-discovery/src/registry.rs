@@ -0,0 +1,289 @@
+use chrono::{DateTime, Utc};
+use common_models::ServiceInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+const REGISTRY_FILE: &str = "registry.json";
+/// How long removals are remembered. Must outlast the longest partition a
+/// replica is expected to come back from; entries older than this from a peer
+/// are refused, since their tombstone may already be gone here.
+const TOMBSTONE_RETENTION_SECS: i64 = 24 * 60 * 60;
+
+/// One versioned registry slot. `service` is `None` for a tombstone, so a
+/// removal replicates like any other write instead of being resurrected by a
+/// replica that still has the old entry.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegistryEntry {
+    pub version: u64,
+    pub origin: String,
+    pub service: Option<ServiceInfo>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl RegistryEntry {
+    /// Last writer wins by Lamport version; the origin replica breaks ties.
+    fn supersedes(&self, other: &RegistryEntry) -> bool {
+        (self.version, &self.origin) > (other.version, &other.origin)
+    }
+}
+
+/// Registry state of one Service Discovery replica, merged with the other
+/// replicas and saved to disk so a restart does not forget every CD.
+pub struct Registry {
+    node_id: String,
+    clock: u64,
+    entries: HashMap<String, RegistryEntry>,
+    path: PathBuf,
+    dirty: bool,
+}
+
+impl Registry {
+    /// Loads `registry.json` from `data_dir`, or starts empty if there is none.
+    pub fn open(node_id: String, data_dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&data_dir)?;
+        let path = data_dir.join(REGISTRY_FILE);
+        let entries: HashMap<String, RegistryEntry> = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            HashMap::new()
+        };
+        let clock = entries.values().map(|e| e.version).max().unwrap_or(0);
+
+        Ok(Registry {
+            node_id,
+            clock,
+            entries,
+            path,
+            dirty: false,
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ServiceInfo> {
+        self.entries.get(id).and_then(|e| e.service.as_ref())
+    }
+
+    pub fn services(&self) -> impl Iterator<Item = &ServiceInfo> {
+        self.entries.values().filter_map(|e| e.service.as_ref())
+    }
+
+    /// Stores `service` as a new local write and returns the entry to replicate.
+    pub fn put(&mut self, service: ServiceInfo) -> (String, RegistryEntry) {
+        let id = service.id.clone();
+        let entry = self.write(id.clone(), Some(service));
+        (id, entry)
+    }
+
+    /// Replaces a live entry with a tombstone. Returns `None` if `id` is not
+    /// registered.
+    pub fn remove(&mut self, id: &str) -> Option<(String, RegistryEntry)> {
+        self.get(id)?;
+        let entry = self.write(id.to_string(), None);
+        Some((id.to_string(), entry))
+    }
+
+    pub fn entries(&self) -> HashMap<String, RegistryEntry> {
+        self.entries.clone()
+    }
+
+    /// Applies entries received from another replica, keeping whichever side
+    /// wrote last. Returns how many entries changed.
+    pub fn merge(&mut self, incoming: HashMap<String, RegistryEntry>) -> usize {
+        let horizon = Utc::now() - chrono::Duration::seconds(TOMBSTONE_RETENTION_SECS);
+        let mut applied = 0;
+        for (id, entry) in incoming {
+            self.clock = self.clock.max(entry.version);
+            let newer = match self.entries.get(&id) {
+                Some(current) => entry.supersedes(current),
+                // Unknown here and older than any tombstone kept: it may have
+                // been removed long ago, so it is not brought back.
+                None => entry.updated_at > horizon,
+            };
+            if newer {
+                self.entries.insert(id, entry);
+                applied += 1;
+            }
+        }
+        if applied > 0 {
+            self.dirty = true;
+        }
+        applied
+    }
+
+    /// Forgets tombstones older than the retention. They only need to live
+    /// long enough for every replica to have seen them.
+    pub fn prune_tombstones(&mut self) {
+        let horizon = Utc::now() - chrono::Duration::seconds(TOMBSTONE_RETENTION_SECS);
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, e| e.service.is_some() || e.updated_at > horizon);
+        if self.entries.len() != before {
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the registry to disk if it changed since the last flush.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&serde_json::to_vec_pretty(&self.entries)?)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn write(&mut self, id: String, service: Option<ServiceInfo>) -> RegistryEntry {
+        self.clock += 1;
+        let entry = RegistryEntry {
+            version: self.clock,
+            origin: self.node_id.clone(),
+            service,
+            updated_at: Utc::now(),
+        };
+        self.entries.insert(id, entry.clone());
+        self.dirty = true;
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replica(node_id: &str) -> Registry {
+        Registry {
+            node_id: node_id.to_string(),
+            clock: 0,
+            entries: HashMap::new(),
+            path: std::env::temp_dir().join("unused-registry.json"),
+            dirty: false,
+        }
+    }
+
+    fn service(id: &str, port: u16) -> ServiceInfo {
+        serde_json::from_value(serde_json::json!({ "id": id, "ip": "127.0.0.1", "port": port }))
+            .unwrap()
+    }
+
+    fn entry(version: u64, origin: &str, service: Option<ServiceInfo>) -> RegistryEntry {
+        RegistryEntry {
+            version,
+            origin: origin.to_string(),
+            service,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn higher_version_wins_and_origin_breaks_ties() {
+        let old = entry(1, "b", None);
+        let new = entry(2, "a", None);
+        assert!(new.supersedes(&old));
+        assert!(!old.supersedes(&new));
+
+        let low_origin = entry(3, "a", None);
+        let high_origin = entry(3, "b", None);
+        assert!(high_origin.supersedes(&low_origin));
+        assert!(!low_origin.supersedes(&high_origin));
+        assert!(!low_origin.supersedes(&low_origin));
+    }
+
+    #[test]
+    fn merge_keeps_the_last_write() {
+        let mut registry = replica("a");
+        registry.put(service("cd_alpha", 8083));
+
+        let stale = entry(0, "b", Some(service("cd_alpha", 9000)));
+        assert_eq!(
+            registry.merge(HashMap::from([("cd_alpha".to_string(), stale)])),
+            0
+        );
+        assert_eq!(registry.get("cd_alpha").unwrap().port, 8083);
+
+        let newer = entry(5, "b", Some(service("cd_alpha", 9000)));
+        assert_eq!(
+            registry.merge(HashMap::from([("cd_alpha".to_string(), newer)])),
+            1
+        );
+        assert_eq!(registry.get("cd_alpha").unwrap().port, 9000);
+
+        // Local writes after a merge get a version above everything seen.
+        let (_, written) = registry.put(service("cd_alpha", 8083));
+        assert_eq!(written.version, 6);
+    }
+
+    #[test]
+    fn tombstones_supersede_older_entries_and_lose_to_newer_ones() {
+        let mut registry = replica("a");
+        registry.put(service("cd_alpha", 8083));
+        let (_, tombstone) = registry.remove("cd_alpha").unwrap();
+        assert!(registry.get("cd_alpha").is_none());
+
+        let resurrection = entry(tombstone.version - 1, "z", Some(service("cd_alpha", 8083)));
+        registry.merge(HashMap::from([("cd_alpha".to_string(), resurrection)]));
+        assert!(registry.get("cd_alpha").is_none());
+
+        let reregistered = entry(tombstone.version + 1, "b", Some(service("cd_alpha", 8083)));
+        registry.merge(HashMap::from([("cd_alpha".to_string(), reregistered)]));
+        assert!(registry.get("cd_alpha").is_some());
+
+        let mut peer = replica("b");
+        peer.put(service("cd_beta", 8084));
+        let (id, tombstone) = peer.remove("cd_beta").unwrap();
+        let mut other = replica("c");
+        other.put(service("cd_beta", 8084));
+        other.merge(HashMap::from([(id, tombstone)]));
+        assert!(other.get("cd_beta").is_none());
+    }
+
+    #[test]
+    fn prunes_only_old_tombstones() {
+        let mut registry = replica("a");
+        let old = Utc::now() - chrono::Duration::seconds(TOMBSTONE_RETENTION_SECS + 1);
+        registry.entries.insert(
+            "gone".to_string(),
+            RegistryEntry {
+                updated_at: old,
+                ..entry(1, "a", None)
+            },
+        );
+        registry
+            .entries
+            .insert("recent".to_string(), entry(2, "a", None));
+        registry.entries.insert(
+            "live".to_string(),
+            RegistryEntry {
+                updated_at: old,
+                ..entry(3, "a", Some(service("live", 1)))
+            },
+        );
+
+        registry.prune_tombstones();
+        assert!(!registry.entries.contains_key("gone"));
+        assert!(registry.entries.contains_key("recent"));
+        assert!(registry.entries.contains_key("live"));
+    }
+
+    #[test]
+    fn refuses_unknown_entries_older_than_the_retention() {
+        let mut registry = replica("a");
+        let old = Utc::now() - chrono::Duration::seconds(TOMBSTONE_RETENTION_SECS + 1);
+        let stale = RegistryEntry {
+            updated_at: old,
+            ..entry(9, "b", Some(service("cd_alpha", 8083)))
+        };
+        assert_eq!(
+            registry.merge(HashMap::from([("cd_alpha".to_string(), stale)])),
+            0
+        );
+        assert!(registry.get("cd_alpha").is_none());
+    }
+}
@@ -1,7 +1,11 @@
-use common_models::ServiceInfo;
-use std::collections::HashMap;
+use super::metrics::Metrics;
+use super::registry::Registry;
+use reqwest::Client;
 use std::sync::{Arc, Mutex};
 
 pub struct AppState {
-    pub registered_services: Arc<Mutex<HashMap<String, ServiceInfo>>>,
+    pub registry: Arc<Mutex<Registry>>,
+    pub peer_urls: Vec<String>,
+    pub http_client: Client,
+    pub metrics: Metrics,
 }
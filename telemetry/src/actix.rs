@@ -0,0 +1,33 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+/// Opens a server span for every request, continuing the trace of the
+/// caller's `traceparent` header when there is one.
+pub type TracingLogger =
+    tracing_actix_web::TracingLogger<tracing_actix_web::DefaultRootSpanBuilder>;
+
+/// Response header with the trace id of the request, to look the request up
+/// in the logs and in the exported traces.
+pub const TRACE_ID_HEADER: &str = "x-trace-id";
+
+/// Middleware adding `x-trace-id` to every response. Wrap it inside
+/// `TracingLogger` (`.wrap(from_fn(trace_id_header)).wrap(TracingLogger::default())`)
+/// so the request span is already open.
+pub async fn trace_id_header(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let trace_id = super::current_trace_id();
+    let mut response = next.call(req).await?;
+    if let Some(trace_id) = trace_id {
+        if let Ok(value) = HeaderValue::from_str(&trace_id.to_string()) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(TRACE_ID_HEADER), value);
+        }
+    }
+    Ok(response)
+}
@@ -0,0 +1,54 @@
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_proto::transform::trace::tonic::group_spans_by_resource_and_scope;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Appends spans to a file in the OTLP JSON encoding, one
+/// `ExportTraceServiceRequest` per line, the format the OpenTelemetry
+/// Collector's `otlpjsonfile` receiver reads.
+#[derive(Debug)]
+pub struct FileExporter {
+    file: Mutex<File>,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl FileExporter {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileExporter {
+            file: Mutex::new(file),
+            resource: ResourceAttributesWithSchema::default(),
+        })
+    }
+
+    fn write(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(batch, &self.resource),
+        };
+        let mut line = serde_json::to_vec(&request)
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)
+            .and_then(|()| file.flush())
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&self, batch: Vec<SpanData>) -> impl Future<Output = OTelSdkResult> + Send {
+        let result = self.write(batch);
+        async move { result }
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
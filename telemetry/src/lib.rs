@@ -0,0 +1,204 @@
+//! Logging and distributed tracing shared by every service.
+//!
+//! Log lines go to stdout through `tracing`, tagged with the service instance
+//! and the trace id of the request they belong to. Spans are OpenTelemetry
+//! spans: the trace context travels between services in the W3C
+//! `traceparent` header, and the spans are exported when configured:
+//!
+//! - `OTEL_EXPORTER_OTLP_ENDPOINT` (or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`):
+//!   OTLP over HTTP to a collector, such as `http://127.0.0.1:4318`
+//! - `TRACES_FILE`: OTLP JSON, one export request per line, appended to a
+//!   local file
+//!
+//! `RUST_LOG` filters both, `info` by default.
+//!
+//! Each service also keeps its own Prometheus registry, served on `/metrics`
+//! through [`metrics_response`].
+
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::{TraceContextExt, TraceId, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::fmt;
+use std::io::IsTerminal;
+use tracing::{Event, Subscriber};
+use tracing_opentelemetry::{OpenTelemetrySpanExt, OtelData};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+mod actix;
+mod file;
+mod metrics;
+
+pub use actix::{trace_id_header, TracingLogger, TRACE_ID_HEADER};
+pub use metrics::metrics_response;
+
+const OTLP_ENDPOINT_VARS: [&str; 2] = [
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+];
+const TRACES_FILE_VAR: &str = "TRACES_FILE";
+
+/// Keeps the span exporters running. Dropping it flushes the spans that are
+/// still buffered, so hold it until the end of `main`.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+/// Installs the global `tracing` subscriber and the trace exporters for
+/// `service_name`. `instance_id` tells replicas apart, in the log prefix and
+/// in the `service.instance.id` of the spans.
+pub fn init(service_name: &'static str, instance_id: &str) -> Telemetry {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let resource = Resource::builder()
+        .with_service_name(service_name)
+        .with_attribute(KeyValue::new(
+            "service.instance.id",
+            instance_id.to_string(),
+        ))
+        .build();
+    let mut provider = SdkTracerProvider::builder().with_resource(resource);
+    let mut exporting = Vec::new();
+
+    if OTLP_ENDPOINT_VARS
+        .iter()
+        .any(|var| std::env::var_os(var).is_some())
+    {
+        match opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+        {
+            Ok(exporter) => {
+                provider = provider.with_batch_exporter(exporter);
+                exporting.push("OTLP collector".to_string());
+            }
+            Err(e) => eprintln!("[{}] Failed to set up OTLP export: {}", instance_id, e),
+        }
+    }
+    if let Some(path) = std::env::var_os(TRACES_FILE_VAR) {
+        match file::FileExporter::open(path.as_ref()) {
+            Ok(exporter) => {
+                provider = provider.with_batch_exporter(exporter);
+                exporting.push(path.to_string_lossy().into_owned());
+            }
+            Err(e) => eprintln!(
+                "[{}] Failed to open {} {:?}: {}",
+                instance_id, TRACES_FILE_VAR, path, e
+            ),
+        }
+    }
+    let provider = provider.build();
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(std::io::stdout().is_terminal())
+                .event_format(LogFormat {
+                    instance: instance_id.to_string(),
+                }),
+        )
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)))
+        .init();
+
+    if !exporting.is_empty() {
+        tracing::info!("Exporting traces to {}", exporting.join(" and "));
+    }
+    Telemetry { provider }
+}
+
+/// Adds the `traceparent` header of `span` to an outbound request, so the
+/// service called continues the same trace.
+pub fn inject_context(span: &tracing::Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// `traceparent` header of the current span, for requests sent without the
+/// typed clients.
+pub fn trace_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    inject_context(&tracing::Span::current(), &mut headers);
+    headers
+}
+
+/// Trace id of the current span, if it belongs to a trace.
+pub fn current_trace_id() -> Option<TraceId> {
+    let trace_id = tracing::Span::current()
+        .context()
+        .span()
+        .span_context()
+        .trace_id();
+    (trace_id != TraceId::INVALID).then_some(trace_id)
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// `2025-06-20T00:00:00.000000Z  INFO [cd_alpha] trace_id=4bf9...: message`,
+/// the `[id]` prefix the services always printed plus the trace the line
+/// belongs to, when there is one.
+struct LogFormat {
+    instance: String,
+}
+
+impl<S, N> FormatEvent<S, N> for LogFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        SystemTime.format_time(&mut writer)?;
+        write!(
+            writer,
+            " {:>5} [{}] ",
+            event.metadata().level(),
+            self.instance
+        )?;
+
+        let trace_id = ctx.lookup_current().and_then(|span| {
+            span.scope()
+                .find_map(|span| span.extensions().get::<OtelData>()?.trace_id())
+        });
+        if let Some(trace_id) = trace_id.filter(|id| *id != TraceId::INVALID) {
+            write!(writer, "trace_id={}: ", trace_id)?;
+        }
+
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
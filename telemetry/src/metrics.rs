@@ -0,0 +1,18 @@
+use actix_web::HttpResponse;
+use prometheus::{Encoder, Registry, TextEncoder};
+
+/// `/metrics` response with every metric of `registry` in the Prometheus text
+/// format.
+pub fn metrics_response(registry: &Registry) -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&registry.gather(), &mut body) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body),
+        Err(e) => {
+            tracing::error!("Failed to encode metrics: {}", e);
+            HttpResponse::InternalServerError().body(format!("Failed to encode metrics: {}", e))
+        }
+    }
+}
@@ -25,17 +25,18 @@ $product = @{
     price = 15.50
     description = "Product for Hub test"
 }
+try { Invoke-RestMethod -Uri "http://127.0.0.1:8082/products/test_hub" -Method DELETE } catch {}
 Invoke-RestMethod -Uri "http://127.0.0.1:8082/products" -Method POST -Body ($product | ConvertTo-Json) -ContentType "application/json"
 Write-Host "   Hub Service OK! Product registered" -ForegroundColor Green
 
 # Start CDs
 Write-Host ""
 Write-Host "3. Starting Distribution Centers..." -ForegroundColor Yellow
-Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service cd_alpha 8083" -WindowStyle Minimized
+Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service -- --config config/cd_alpha.toml" -WindowStyle Minimized
 Start-Sleep -Seconds 3
-Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service cd_beta 8084" -WindowStyle Minimized
+Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service -- --config config/cd_beta.toml" -WindowStyle Minimized
 Start-Sleep -Seconds 3
-Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service cd_gamma 8085" -WindowStyle Minimized
+Start-Process powershell -ArgumentList "-Command", "cd '$PWD'; cargo run --bin cd-service -- --config config/cd_gamma.toml" -WindowStyle Minimized
 Start-Sleep -Seconds 15
 
 # Check if CDs registered with Service Discovery
@@ -62,7 +63,7 @@ Write-Host "   CD Gamma OK! Phones: $($cd_gamma.quantity) units" -ForegroundColo
 Write-Host ""
 Write-Host "4. Testing product availability query..." -ForegroundColor Yellow
 $who_has = Invoke-RestMethod -Uri "http://127.0.0.1:8082/who_has_product/celulares/5" -Method GET
-Write-Host "   Query OK! CDs with phones: $($who_has.Count)" -ForegroundColor Green
+Write-Host "   Query OK! CDs with phones: $($who_has.cds.Count)" -ForegroundColor Green
 
 # Test product transfer between CDs
 Write-Host ""
@@ -76,8 +77,15 @@ Write-Host "   CD Alpha - Initial phones: $($cd_alpha_phones_initial.quantity) u
 $cd_gamma_phones_initial = Invoke-RestMethod -Uri "http://127.0.0.1:8085/inventory/celulares" -Method GET
 Write-Host "   CD Gamma - Initial phones: $($cd_gamma_phones_initial.quantity) units" -ForegroundColor White
 
-Write-Host "   Waiting for automatic transfers..." -ForegroundColor Cyan
-Start-Sleep -Seconds 15
+Write-Host "   Requesting 12 phones for CD Alpha..." -ForegroundColor Cyan
+$product_request = @{
+    product_code = "celulares"
+    quantity = 12
+}
+$request_status = Invoke-RestMethod -Uri "http://127.0.0.1:8083/requests" -Method POST -Body ($product_request | ConvertTo-Json) -ContentType "application/json"
+Start-Sleep -Seconds 5
+$request_status = Invoke-RestMethod -Uri "http://127.0.0.1:8083/requests/$($request_status.request_id)" -Method GET
+Write-Host "   Request $($request_status.request_id): $($request_status.state) ($($request_status.quantity_received) received)" -ForegroundColor White
 
 # Test manual transfer
 Write-Host "   Testing manual transfer..." -ForegroundColor Cyan